@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Longest capability list we put on a single `CAP REQ` line, keeping the
+/// whole line comfortably below the 512 byte limit.
+const MAX_REQ_PAYLOAD: usize = 400;
+
+/// Capabilities requested when a saved connection does not specify its own.
+pub fn default_requested_caps() -> Vec<String> {
+    [
+        "message-tags",
+        "server-time",
+        "multi-prefix",
+        "account-tag",
+        "account-notify",
+        "away-notify",
+        "extended-join",
+        "chghost",
+        "cap-notify",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+/// IRCv3 capability negotiation (`CAP LS 302` / `REQ` / `ACK` / `NAK` and the
+/// runtime `NEW` / `DEL` notifications) for a single connection.
+#[derive(Debug, Default)]
+pub struct CapNegotiator {
+    wanted: Vec<String>,
+    available: BTreeMap<String, Option<String>>,
+    enabled: BTreeSet<String>,
+    pending: BTreeSet<String>,
+    ls_complete: bool,
+    ended: bool,
+}
+
+/// What the connection has to do after feeding a `CAP` message to the
+/// negotiator.
+#[derive(Debug, Default)]
+pub struct CapOutcome {
+    /// Raw lines to send to the server, in order.
+    pub lines: Vec<String>,
+    /// The enabled capability set changed.
+    pub enabled_changed: bool,
+    /// Initial negotiation has settled and registration can be resumed with
+    /// `CAP END`.
+    pub finished: bool,
+}
+
+impl CapNegotiator {
+    pub fn new(wanted: &[String]) -> Self {
+        Self {
+            wanted: wanted
+                .iter()
+                .map(|cap| cap.trim().to_ascii_lowercase())
+                .filter(|cap| !cap.is_empty())
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn enabled(&self) -> Vec<String> {
+        self.enabled.iter().cloned().collect()
+    }
+
+    /// The value advertised for `cap` in `CAP LS 302`, e.g. `PLAIN,EXTERNAL`
+    /// for `sasl=PLAIN,EXTERNAL`.
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap).and_then(|value| value.as_deref())
+    }
+
+    /// Whether registration is still held open waiting for `CAP END`.
+    pub fn is_negotiating(&self) -> bool {
+        !self.ended
+    }
+
+    /// Marks initial negotiation as over, either because we sent `CAP END` or
+    /// because the server registered us without ever answering `CAP LS`.
+    pub fn end(&mut self) {
+        self.ended = true;
+        self.pending.clear();
+    }
+
+    /// Handles the arguments of a `CAP` command, starting with the target
+    /// (`*` or our nick) followed by the subcommand.
+    pub fn handle(&mut self, args: &[&str]) -> CapOutcome {
+        let mut outcome = CapOutcome::default();
+        let Some(subcommand) = args.get(1) else {
+            return outcome;
+        };
+        match subcommand.to_ascii_uppercase().as_str() {
+            "LS" => {
+                // `CAP * LS * :caps` marks a continuation of a multi-line reply.
+                let more = args.len() > 3 && args[2] == "*";
+                let list = if more { args.get(3) } else { args.get(2) };
+                for (name, value) in list.map(|l| parse_cap_list(l)).unwrap_or_default() {
+                    self.available.insert(name, value);
+                }
+                if !more && !self.ls_complete {
+                    self.ls_complete = true;
+                    let wanted = self.wanted_unrequested();
+                    outcome.lines = req_lines(&wanted);
+                    self.pending.extend(wanted);
+                    outcome.finished = self.settled();
+                }
+            }
+            "ACK" => {
+                for (name, _) in args.get(2).map(|l| parse_cap_list(l)).unwrap_or_default() {
+                    if let Some(name) = name.strip_prefix('-') {
+                        self.pending.remove(name);
+                        outcome.enabled_changed |= self.enabled.remove(name);
+                    } else {
+                        self.pending.remove(&name);
+                        outcome.enabled_changed |= self.enabled.insert(name);
+                    }
+                }
+                outcome.finished = self.settled();
+            }
+            "NAK" => {
+                for (name, _) in args.get(2).map(|l| parse_cap_list(l)).unwrap_or_default() {
+                    tracing::debug!("server refused capability {name}");
+                    self.pending.remove(&name);
+                }
+                outcome.finished = self.settled();
+            }
+            "NEW" => {
+                for (name, value) in args.get(2).map(|l| parse_cap_list(l)).unwrap_or_default() {
+                    self.available.insert(name, value);
+                }
+                let wanted = self.wanted_unrequested();
+                outcome.lines = req_lines(&wanted);
+                self.pending.extend(wanted);
+            }
+            "DEL" => {
+                for (name, _) in args.get(2).map(|l| parse_cap_list(l)).unwrap_or_default() {
+                    self.available.remove(&name);
+                    self.pending.remove(&name);
+                    outcome.enabled_changed |= self.enabled.remove(&name);
+                }
+            }
+            _ => {}
+        }
+        outcome
+    }
+
+    fn wanted_unrequested(&self) -> Vec<String> {
+        self.wanted
+            .iter()
+            .filter(|cap| self.available.contains_key(*cap))
+            .filter(|cap| !self.enabled.contains(*cap) && !self.pending.contains(*cap))
+            .cloned()
+            .collect()
+    }
+
+    fn settled(&self) -> bool {
+        !self.ended && self.ls_complete && self.pending.is_empty()
+    }
+}
+
+fn parse_cap_list(list: &str) -> Vec<(String, Option<String>)> {
+    list.split_whitespace()
+        .map(|entry| match entry.split_once('=') {
+            Some((name, value)) => (name.to_ascii_lowercase(), Some(value.to_string())),
+            None => (entry.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+fn req_lines(caps: &[String]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for cap in caps {
+        if !current.is_empty() && current.len() + 1 + cap.len() > MAX_REQ_PAYLOAD {
+            lines.push(format!("CAP REQ :{current}"));
+            current.clear();
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(cap);
+    }
+    if !current.is_empty() {
+        lines.push(format!("CAP REQ :{current}"));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiator(wanted: &[&str]) -> CapNegotiator {
+        let wanted: Vec<String> = wanted.iter().map(|cap| cap.to_string()).collect();
+        CapNegotiator::new(&wanted)
+    }
+
+    #[test]
+    fn ls_302_waits_for_the_last_line_before_requesting() {
+        let mut caps = negotiator(&["multi-prefix", "sasl", "server-time"]);
+        let outcome = caps.handle(&["*", "LS", "*", "multi-prefix sasl=PLAIN,EXTERNAL"]);
+        assert!(outcome.lines.is_empty());
+        assert!(!outcome.finished);
+
+        let outcome = caps.handle(&["*", "LS", "server-time away-notify"]);
+        assert_eq!(outcome.lines, ["CAP REQ :multi-prefix sasl server-time"]);
+        assert!(!outcome.finished);
+        assert_eq!(caps.value("sasl"), Some("PLAIN,EXTERNAL"));
+        assert_eq!(caps.value("multi-prefix"), None);
+    }
+
+    #[test]
+    fn ack_and_nak_settle_negotiation() {
+        let mut caps = negotiator(&["multi-prefix", "sasl", "server-time"]);
+        caps.handle(&["*", "LS", "multi-prefix sasl server-time"]);
+
+        let outcome = caps.handle(&["fluxchat", "ACK", "multi-prefix sasl"]);
+        assert!(outcome.enabled_changed);
+        assert!(!outcome.finished);
+        let outcome = caps.handle(&["fluxchat", "NAK", "server-time"]);
+        assert!(!outcome.enabled_changed);
+        assert!(outcome.finished);
+        assert_eq!(caps.enabled(), ["multi-prefix", "sasl"]);
+        assert!(caps.is_enabled("sasl"));
+        assert!(!caps.is_enabled("server-time"));
+    }
+
+    #[test]
+    fn finishes_at_once_when_nothing_wanted_is_offered() {
+        let mut caps = negotiator(&["sasl"]);
+        let outcome = caps.handle(&["*", "LS", "away-notify"]);
+        assert!(outcome.lines.is_empty());
+        assert!(outcome.finished);
+    }
+
+    #[test]
+    fn names_and_subcommands_are_case_insensitive() {
+        let mut caps = negotiator(&["Multi-Prefix"]);
+        let outcome = caps.handle(&["*", "ls", "MULTI-PREFIX"]);
+        assert_eq!(outcome.lines, ["CAP REQ :multi-prefix"]);
+        caps.handle(&["*", "ack", "multi-prefix"]);
+        assert!(caps.is_enabled("multi-prefix"));
+    }
+
+    #[test]
+    fn new_requests_wanted_caps_after_registration() {
+        let mut caps = negotiator(&["away-notify", "chghost"]);
+        caps.handle(&["*", "LS", "away-notify"]);
+        caps.handle(&["fluxchat", "ACK", "away-notify"]);
+        caps.end();
+        assert!(!caps.is_negotiating());
+
+        let outcome = caps.handle(&["fluxchat", "NEW", "chghost batch"]);
+        assert_eq!(outcome.lines, ["CAP REQ :chghost"]);
+        let outcome = caps.handle(&["fluxchat", "ACK", "chghost"]);
+        assert!(outcome.enabled_changed);
+        // Registration is already over; there's no second `CAP END`.
+        assert!(!outcome.finished);
+        // Offering it again doesn't request it twice.
+        let outcome = caps.handle(&["fluxchat", "NEW", "chghost"]);
+        assert!(outcome.lines.is_empty());
+    }
+
+    #[test]
+    fn del_drops_enabled_caps() {
+        let mut caps = negotiator(&["away-notify", "sasl"]);
+        caps.handle(&["*", "LS", "away-notify sasl=PLAIN"]);
+        caps.handle(&["fluxchat", "ACK", "away-notify sasl"]);
+
+        let outcome = caps.handle(&["fluxchat", "DEL", "sasl"]);
+        assert!(outcome.enabled_changed);
+        assert_eq!(caps.enabled(), ["away-notify"]);
+        assert_eq!(caps.value("sasl"), None);
+        let outcome = caps.handle(&["fluxchat", "DEL", "batch"]);
+        assert!(!outcome.enabled_changed);
+    }
+
+    #[test]
+    fn ack_with_a_dash_disables() {
+        let mut caps = negotiator(&["away-notify"]);
+        caps.handle(&["*", "LS", "away-notify"]);
+        caps.handle(&["fluxchat", "ACK", "away-notify"]);
+        let outcome = caps.handle(&["fluxchat", "ACK", "-away-notify"]);
+        assert!(outcome.enabled_changed);
+        assert!(caps.enabled().is_empty());
+    }
+
+    #[test]
+    fn long_requests_are_spread_over_several_lines() {
+        let wanted: Vec<String> = (0..60).map(|n| format!("vendor.example/cap-{n}")).collect();
+        let mut caps = CapNegotiator::new(&wanted);
+        let offered = wanted.join(" ");
+        let outcome = caps.handle(&["*", "LS", &offered]);
+        assert!(outcome.lines.len() > 1);
+        let mut requested = Vec::new();
+        for line in &outcome.lines {
+            let payload = line.strip_prefix("CAP REQ :").unwrap();
+            assert!(payload.len() <= MAX_REQ_PAYLOAD);
+            requested.extend(payload.split(' ').map(str::to_string));
+        }
+        assert_eq!(requested, wanted);
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    isupport::{prefix_mode_name, CaseMapping, ServerInfo},
+    messages::ChannelUserInfo,
+};
+
+/// The channels we are in, who else is in them, and the nicks we have open
+/// private conversations with, all keyed by casefolded name.
+#[derive(Debug, Default)]
+pub struct Channels {
+    channels: BTreeMap<String, Channel>,
+    /// Folded nick -> nick as last seen.
+    queries: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+struct Channel {
+    name: String,
+    topic: Option<String>,
+    topic_setter: Option<String>,
+    /// Milliseconds since the epoch.
+    topic_time: Option<i64>,
+    /// Milliseconds since the epoch, from `RPL_CREATIONTIME` (329).
+    created: Option<i64>,
+    /// Modes currently set on the channel, with their parameter if any. List
+    /// modes such as bans are not tracked.
+    modes: BTreeMap<char, Option<String>>,
+    /// Folded nick -> member.
+    members: BTreeMap<String, Member>,
+    /// `NAMES` entries collected since the last `RPL_ENDOFNAMES` (366).
+    pending_names: Option<BTreeMap<String, Member>>,
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    nick: String,
+    /// Membership mode letters such as `o` and `v`.
+    modes: Vec<char>,
+}
+
+/// One change from a `MODE` line, with its parameter already matched up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub param: Option<String>,
+}
+
+/// What the UI needs to redraw a channel from scratch.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelSnapshot {
+    pub name: String,
+    pub topic: Option<String>,
+    pub topic_setter: Option<String>,
+    pub topic_time: Option<i64>,
+    pub created: Option<i64>,
+    pub modes: BTreeMap<char, Option<String>>,
+    /// Highest rank first, then by nick.
+    pub members: Vec<ChannelUserInfo>,
+}
+
+impl Channel {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            topic: None,
+            topic_setter: None,
+            topic_time: None,
+            created: None,
+            modes: BTreeMap::new(),
+            members: BTreeMap::new(),
+            pending_names: None,
+        }
+    }
+}
+
+impl Channels {
+    /// Records that we joined `channel`.
+    pub fn join(&mut self, casemapping: CaseMapping, channel: &str) {
+        self.channels
+            .entry(casemapping.fold(channel))
+            .or_insert_with(|| Channel::new(channel));
+    }
+
+    /// Records that we left `channel`, forgetting its state.
+    pub fn part(&mut self, casemapping: CaseMapping, channel: &str) {
+        self.channels.remove(&casemapping.fold(channel));
+    }
+
+    /// The channels we are in, spelled as we joined them.
+    pub fn names(&self) -> Vec<String> {
+        self.channels
+            .values()
+            .map(|channel| channel.name.clone())
+            .collect()
+    }
+
+    pub fn add_member(&mut self, casemapping: CaseMapping, channel: &str, nick: &str) {
+        if let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) {
+            channel
+                .members
+                .entry(casemapping.fold(nick))
+                .or_insert_with(|| Member {
+                    nick: nick.to_string(),
+                    modes: Vec::new(),
+                });
+        }
+    }
+
+    pub fn remove_member(&mut self, casemapping: CaseMapping, channel: &str, nick: &str) {
+        if let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) {
+            channel.members.remove(&casemapping.fold(nick));
+        }
+    }
+
+    /// Forgets `nick` in every channel, returning the channels they were in.
+    pub fn remove_everywhere(&mut self, casemapping: CaseMapping, nick: &str) -> Vec<String> {
+        let key = casemapping.fold(nick);
+        self.channels
+            .values_mut()
+            .filter_map(|channel| channel.members.remove(&key).map(|_| channel.name.clone()))
+            .collect()
+    }
+
+    /// Records one `RPL_NAMREPLY` (353) entry. The member list is replaced
+    /// once the matching `RPL_ENDOFNAMES` arrives. Returns `false` when we
+    /// are not in `channel`, in which case nothing is recorded.
+    pub fn names_entry(
+        &mut self,
+        casemapping: CaseMapping,
+        channel: &str,
+        nick: &str,
+        modes: Vec<char>,
+    ) -> bool {
+        let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) else {
+            return false;
+        };
+        channel
+            .pending_names
+            .get_or_insert_with(BTreeMap::new)
+            .insert(
+                casemapping.fold(nick),
+                Member {
+                    nick: nick.to_string(),
+                    modes,
+                },
+            );
+        true
+    }
+
+    /// Handles `RPL_ENDOFNAMES` (366), returning the finished member list in
+    /// snapshot order, or `None` when we are not in `channel`.
+    pub fn names_end(
+        &mut self,
+        server: &ServerInfo,
+        channel: &str,
+    ) -> Option<Vec<ChannelUserInfo>> {
+        let state = self.channels.get_mut(&server.casemapping.fold(channel))?;
+        if let Some(members) = state.pending_names.take() {
+            state.members = members;
+        }
+        self.snapshot(server, channel)
+            .map(|snapshot| snapshot.members)
+    }
+
+    /// Sets the topic, forgetting who set it until told otherwise.
+    pub fn set_topic(&mut self, casemapping: CaseMapping, channel: &str, topic: Option<String>) {
+        if let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) {
+            channel.topic = topic.filter(|topic| !topic.is_empty());
+            channel.topic_setter = None;
+            channel.topic_time = None;
+        }
+    }
+
+    /// Records who set the topic and when (milliseconds since the epoch).
+    pub fn set_topic_who(
+        &mut self,
+        casemapping: CaseMapping,
+        channel: &str,
+        setter: &str,
+        time: Option<i64>,
+    ) {
+        if let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) {
+            channel.topic_setter = Some(setter.to_string());
+            channel.topic_time = time;
+        }
+    }
+
+    pub fn topic(&self, casemapping: CaseMapping, channel: &str) -> Option<String> {
+        self.channels
+            .get(&casemapping.fold(channel))
+            .and_then(|channel| channel.topic.clone())
+    }
+
+    pub fn set_created(&mut self, casemapping: CaseMapping, channel: &str, created: i64) {
+        if let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) {
+            channel.created = Some(created);
+        }
+    }
+
+    /// Applies parsed `MODE` changes, updating member prefixes and the
+    /// channel's own modes. Returns the members whose prefixes changed.
+    pub fn apply_modes(
+        &mut self,
+        server: &ServerInfo,
+        channel: &str,
+        changes: &[ModeChange],
+    ) -> Vec<ChannelUserInfo> {
+        let casemapping = server.casemapping;
+        let Some(channel) = self.channels.get_mut(&casemapping.fold(channel)) else {
+            return Vec::new();
+        };
+        let mut changed = Vec::new();
+        for change in changes {
+            if server.prefix_for_mode(change.mode).is_some() {
+                let Some(key) = change.param.as_deref().map(|nick| casemapping.fold(nick)) else {
+                    continue;
+                };
+                let Some(member) = channel.members.get_mut(&key) else {
+                    continue;
+                };
+                member.modes.retain(|mode| *mode != change.mode);
+                if change.adding {
+                    member.modes.push(change.mode);
+                }
+                if !changed.contains(&key) {
+                    changed.push(key);
+                }
+            } else if server.chanmodes.list.contains(change.mode) {
+                continue;
+            } else if change.adding {
+                channel.modes.insert(change.mode, change.param.clone());
+            } else {
+                channel.modes.remove(&change.mode);
+            }
+        }
+        changed
+            .iter()
+            .filter_map(|key| channel.members.get(key))
+            .map(|member| member_info(server, member))
+            .collect()
+    }
+
+    /// Handles `RPL_CHANNELMODEIS` (324), which lists every mode currently set.
+    pub fn set_modes(&mut self, server: &ServerInfo, channel: &str, changes: &[ModeChange]) {
+        if let Some(state) = self.channels.get_mut(&server.casemapping.fold(channel)) {
+            state.modes.clear();
+        }
+        self.apply_modes(server, channel, changes);
+    }
+
+    pub fn modes(
+        &self,
+        casemapping: CaseMapping,
+        channel: &str,
+    ) -> Option<BTreeMap<char, Option<String>>> {
+        self.channels
+            .get(&casemapping.fold(channel))
+            .map(|channel| channel.modes.clone())
+    }
+
+    /// Notes a private conversation with `nick`.
+    pub fn add_query(&mut self, casemapping: CaseMapping, nick: &str) {
+        self.queries
+            .insert(casemapping.fold(nick), nick.to_string());
+    }
+
+    /// Renames `old` to `new` everywhere and returns the channels and queries
+    /// they share with us, i.e. where their change of name should be shown.
+    /// Queries are returned under the new nick.
+    pub fn rename(&mut self, casemapping: CaseMapping, old: &str, new: &str) -> Vec<String> {
+        let old_key = casemapping.fold(old);
+        let new_key = casemapping.fold(new);
+        let mut shared = Vec::new();
+        for channel in self.channels.values_mut() {
+            if let Some(mut member) = channel.members.remove(&old_key) {
+                member.nick = new.to_string();
+                channel.members.insert(new_key.clone(), member);
+                shared.push(channel.name.clone());
+            }
+        }
+        if self.queries.remove(&old_key).is_some() {
+            self.queries.insert(new_key, new.to_string());
+            shared.push(new.to_string());
+        }
+        shared
+    }
+
+    /// Renames ourselves. Every channel and query is shared with us.
+    pub fn rename_self(&mut self, casemapping: CaseMapping, old: &str, new: &str) -> Vec<String> {
+        self.rename(casemapping, old, new);
+        self.names()
+            .into_iter()
+            .chain(self.queries.values().cloned())
+            .collect()
+    }
+
+    pub fn snapshot(&self, server: &ServerInfo, channel: &str) -> Option<ChannelSnapshot> {
+        let channel = self.channels.get(&server.casemapping.fold(channel))?;
+        let mut members = channel.members.values().collect::<Vec<_>>();
+        members.sort_by_cached_key(|member| {
+            let rank = member
+                .modes
+                .iter()
+                .map(|mode| mode_rank(server, *mode))
+                .min()
+                .unwrap_or(usize::MAX);
+            (rank, server.casemapping.fold(&member.nick))
+        });
+        Some(ChannelSnapshot {
+            name: channel.name.clone(),
+            topic: channel.topic.clone(),
+            topic_setter: channel.topic_setter.clone(),
+            topic_time: channel.topic_time,
+            created: channel.created,
+            modes: channel.modes.clone(),
+            members: members
+                .into_iter()
+                .map(|member| member_info(server, member))
+                .collect(),
+        })
+    }
+
+    /// Snapshots of every channel we are in.
+    pub fn snapshots(&self, server: &ServerInfo) -> Vec<ChannelSnapshot> {
+        self.channels
+            .values()
+            .filter_map(|channel| self.snapshot(server, &channel.name))
+            .collect()
+    }
+}
+
+fn mode_rank(server: &ServerInfo, mode: char) -> usize {
+    server
+        .prefixes
+        .iter()
+        .position(|(prefix_mode, _)| *prefix_mode == mode)
+        .unwrap_or(usize::MAX)
+}
+
+/// A member as the UI sees it, with mode names ordered highest rank first.
+fn member_info(server: &ServerInfo, member: &Member) -> ChannelUserInfo {
+    let mut modes = member.modes.clone();
+    modes.sort_by_key(|mode| mode_rank(server, *mode));
+    ChannelUserInfo {
+        nick: member.nick.clone(),
+        modes: modes.into_iter().map(prefix_mode_name).collect(),
+    }
+}
+
+/// Splits a `MODE` mode string and its arguments into individual changes,
+/// using `PREFIX` and `CHANMODES` to decide which modes consume an argument.
+pub fn parse_mode_changes<S: AsRef<str>>(
+    server: &ServerInfo,
+    modes: &str,
+    args: &[S],
+) -> Vec<ModeChange> {
+    let mut args = args.iter().map(|arg| arg.as_ref().to_string());
+    let mut adding = true;
+    let mut changes = Vec::new();
+    for mode in modes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            _ => {
+                let chanmodes = &server.chanmodes;
+                let takes_param = server.prefix_for_mode(mode).is_some()
+                    || chanmodes.list.contains(mode)
+                    || chanmodes.always_param.contains(mode)
+                    || (adding && chanmodes.set_param.contains(mode));
+                changes.push(ModeChange {
+                    adding,
+                    mode,
+                    param: if takes_param { args.next() } else { None },
+                });
+            }
+        }
+    }
+    changes
+}
+
+/// Renders changes back into a compact mode string such as `+ov-k alice bob`.
+pub fn format_mode_changes(changes: &[ModeChange]) -> String {
+    let mut modes = String::new();
+    let mut params = Vec::new();
+    let mut sign = None;
+    for change in changes {
+        if sign != Some(change.adding) {
+            modes.push(if change.adding { '+' } else { '-' });
+            sign = Some(change.adding);
+        }
+        modes.push(change.mode);
+        params.extend(change.param.as_deref());
+    }
+    std::iter::once(modes.as_str())
+        .chain(params)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server with every mode class in use: `PREFIX=(qaohv)~&@%+` and
+    /// `CHANMODES=beI,k,l,imnst`.
+    fn server() -> ServerInfo {
+        let mut server = ServerInfo::default();
+        server.apply(&["PREFIX=(qaohv)~&@%+", "CHANMODES=beI,k,l,imnst"]);
+        server
+    }
+
+    fn change(adding: bool, mode: char, param: Option<&str>) -> ModeChange {
+        ModeChange {
+            adding,
+            mode,
+            param: param.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn each_mode_class_takes_params_as_chanmodes_says() {
+        let changes = parse_mode_changes(
+            &server(),
+            "+bklim-lk+o",
+            &["*!*@spam", "secret", "10", "key", "alice"],
+        );
+        assert_eq!(
+            changes,
+            [
+                // A: list modes always take a param.
+                change(true, 'b', Some("*!*@spam")),
+                // B: always takes a param, even when unset.
+                change(true, 'k', Some("secret")),
+                // C: only when set.
+                change(true, 'l', Some("10")),
+                // D: never.
+                change(true, 'i', None),
+                change(true, 'm', None),
+                change(false, 'l', None),
+                change(false, 'k', Some("key")),
+                // PREFIX modes always take a nick.
+                change(true, 'o', Some("alice")),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefix_modes_follow_the_servers_prefix_token() {
+        let changes = parse_mode_changes(&server(), "+qah-v", &["a", "b", "c", "d"]);
+        assert_eq!(
+            changes,
+            [
+                change(true, 'q', Some("a")),
+                change(true, 'a', Some("b")),
+                change(true, 'h', Some("c")),
+                change(false, 'v', Some("d")),
+            ]
+        );
+        // Without `q` in PREFIX it's just an unknown flag.
+        let changes = parse_mode_changes(&ServerInfo::default(), "+qo", &["alice"]);
+        assert_eq!(
+            changes,
+            [change(true, 'q', None), change(true, 'o', Some("alice"))]
+        );
+    }
+
+    #[test]
+    fn missing_params_are_none() {
+        let changes = parse_mode_changes(&server(), "+ov", &["alice"]);
+        assert_eq!(
+            changes,
+            [change(true, 'o', Some("alice")), change(true, 'v', None)]
+        );
+    }
+
+    #[test]
+    fn user_modes_parse_with_no_params() {
+        let changes = parse_mode_changes::<&str>(&server(), "+iw-x", &[]);
+        assert_eq!(
+            changes,
+            [
+                change(true, 'i', None),
+                change(true, 'w', None),
+                change(false, 'x', None),
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_changes_compactly() {
+        let changes = parse_mode_changes(&server(), "+o+v-k-l", &["alice", "bob", "key"]);
+        assert_eq!(format_mode_changes(&changes), "+ov-kl alice bob key");
+        assert_eq!(format_mode_changes(&[]), "");
+    }
+
+    #[test]
+    fn apply_updates_member_prefixes_and_channel_modes() {
+        let server = server();
+        let casemapping = server.casemapping;
+        let mut channels = Channels::default();
+        channels.join(casemapping, "#c");
+        channels.add_member(casemapping, "#c", "Alice");
+        channels.add_member(casemapping, "#c", "bob");
+
+        let changes =
+            parse_mode_changes(&server, "+ovntkb-i", &["alice", "ALICE", "key", "*!*@spam"]);
+        let changed = channels.apply_modes(&server, "#c", &changes);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].nick, "Alice");
+        assert_eq!(changed[0].modes, ["op", "voice"]);
+
+        let modes = channels.modes(casemapping, "#c").unwrap();
+        // List modes such as bans aren't channel state.
+        assert_eq!(
+            modes.into_iter().collect::<Vec<_>>(),
+            [('k', Some("key".into())), ('n', None), ('t', None)]
+        );
+
+        let changes = parse_mode_changes(&server, "-o-k", &["alice", "key"]);
+        let changed = channels.apply_modes(&server, "#c", &changes);
+        assert_eq!(changed[0].modes, ["voice"]);
+        assert!(!channels
+            .modes(casemapping, "#c")
+            .unwrap()
+            .contains_key(&'k'));
+    }
+
+    #[test]
+    fn channel_mode_reply_replaces_the_modes() {
+        let server = server();
+        let mut channels = Channels::default();
+        channels.join(server.casemapping, "#c");
+        let changes = parse_mode_changes::<&str>(&server, "+mi", &[]);
+        channels.apply_modes(&server, "#c", &changes);
+
+        let changes = parse_mode_changes(&server, "+nl", &["5"]);
+        channels.set_modes(&server, "#c", &changes);
+        let modes = channels.modes(server.casemapping, "#c").unwrap();
+        assert_eq!(
+            modes.into_iter().collect::<Vec<_>>(),
+            [('l', Some("5".into())), ('n', None)]
+        );
+    }
+
+    #[test]
+    fn modes_for_unknown_channels_or_members_are_ignored() {
+        let server = server();
+        let mut channels = Channels::default();
+        let changes = parse_mode_changes(&server, "+o", &["alice"]);
+        assert!(channels.apply_modes(&server, "#nope", &changes).is_empty());
+        channels.join(server.casemapping, "#c");
+        assert!(channels.apply_modes(&server, "#c", &changes).is_empty());
+    }
+
+    fn nicks(channels: &Channels, server: &ServerInfo, channel: &str) -> Vec<String> {
+        channels
+            .snapshot(server, channel)
+            .map(|snapshot| snapshot.members.into_iter().map(|user| user.nick).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn channels_and_members_are_keyed_case_insensitively() {
+        let server = server();
+        let casemapping = server.casemapping;
+        let mut channels = Channels::default();
+        channels.join(casemapping, "#Rust[dev]");
+        channels.join(casemapping, "#rust{DEV}");
+        assert_eq!(channels.names(), ["#Rust[dev]"]);
+
+        channels.add_member(casemapping, "#RUST{dev}", "Alice");
+        channels.add_member(casemapping, "#rust[dev]", "ALICE");
+        assert_eq!(nicks(&channels, &server, "#rust{dev}"), ["Alice"]);
+        channels.remove_member(casemapping, "#rust[DEV]", "alice");
+        assert!(nicks(&channels, &server, "#rust[dev]").is_empty());
+
+        channels.part(casemapping, "#RUST[DEV]");
+        assert!(channels.names().is_empty());
+        assert!(channels.snapshot(&server, "#rust[dev]").is_none());
+    }
+
+    #[test]
+    fn names_replace_the_member_list_at_end_of_names() {
+        let server = server();
+        let casemapping = server.casemapping;
+        let mut channels = Channels::default();
+        channels.join(casemapping, "#c");
+        channels.add_member(casemapping, "#c", "gone");
+        assert!(channels.names_entry(casemapping, "#c", "bob", vec!['v']));
+        assert!(channels.names_entry(casemapping, "#c", "alice", vec!['o', 'v']));
+        assert!(channels.names_entry(casemapping, "#c", "carol", Vec::new()));
+        // Until 366 the old list stands.
+        assert_eq!(nicks(&channels, &server, "#c"), ["gone"]);
+
+        let members = channels.names_end(&server, "#c").unwrap();
+        let ranked: Vec<_> = members
+            .iter()
+            .map(|user| (user.nick.as_str(), user.modes.clone()))
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                ("alice", vec!["op".to_string(), "voice".to_string()]),
+                ("bob", vec!["voice".to_string()]),
+                ("carol", Vec::new()),
+            ]
+        );
+        assert!(!channels.names_entry(casemapping, "#other", "bob", Vec::new()));
+        assert!(channels.names_end(&server, "#other").is_none());
+    }
+
+    #[test]
+    fn renames_and_quits_reach_every_shared_channel() {
+        let server = server();
+        let casemapping = server.casemapping;
+        let mut channels = Channels::default();
+        for channel in ["#a", "#b", "#c"] {
+            channels.join(casemapping, channel);
+            channels.add_member(casemapping, channel, "me");
+        }
+        channels.add_member(casemapping, "#a", "bob");
+        channels.add_member(casemapping, "#b", "bob");
+        channels.add_query(casemapping, "Bob");
+
+        assert_eq!(
+            channels.rename(casemapping, "BOB", "robert"),
+            ["#a", "#b", "robert"]
+        );
+        assert_eq!(nicks(&channels, &server, "#a"), ["me", "robert"]);
+        assert_eq!(
+            channels.rename_self(casemapping, "me", "myself"),
+            ["#a", "#b", "#c", "robert"]
+        );
+        assert_eq!(nicks(&channels, &server, "#c"), ["myself"]);
+
+        assert_eq!(
+            channels.remove_everywhere(casemapping, "Robert"),
+            ["#a", "#b"]
+        );
+        assert_eq!(nicks(&channels, &server, "#b"), ["myself"]);
+    }
+
+    #[test]
+    fn topic_and_creation_time() {
+        let casemapping = CaseMapping::Rfc1459;
+        let mut channels = Channels::default();
+        channels.join(casemapping, "#c");
+        channels.set_topic(casemapping, "#C", Some("hello".into()));
+        channels.set_topic_who(casemapping, "#c", "alice", Some(1_700_000_000_000));
+        channels.set_created(casemapping, "#c", 1_600_000_000_000);
+        let snapshot = channels.snapshot(&ServerInfo::default(), "#c").unwrap();
+        assert_eq!(snapshot.topic.as_deref(), Some("hello"));
+        assert_eq!(snapshot.topic_setter.as_deref(), Some("alice"));
+        assert_eq!(snapshot.topic_time, Some(1_700_000_000_000));
+        assert_eq!(snapshot.created, Some(1_600_000_000_000));
+
+        // A new topic forgets the old setter; an empty one clears it.
+        channels.set_topic(casemapping, "#c", Some(String::new()));
+        assert_eq!(channels.topic(casemapping, "#c"), None);
+        let snapshot = channels.snapshot(&ServerInfo::default(), "#c").unwrap();
+        assert_eq!(snapshot.topic_setter, None);
+    }
+
+    #[test]
+    fn snapshots_cover_every_joined_channel() {
+        let server = server();
+        let mut channels = Channels::default();
+        channels.join(server.casemapping, "#b");
+        channels.join(server.casemapping, "#a");
+        let names: Vec<_> = channels
+            .snapshots(&server)
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .collect();
+        assert_eq!(names, ["#a", "#b"]);
+    }
+}
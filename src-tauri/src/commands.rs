@@ -1,6 +1,23 @@
-use serde::Deserialize;
+use std::collections::BTreeMap;
 
-use crate::{connection::ConnectionConfig, messages::ChatMessage, state::AppState};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capabilities::default_requested_caps,
+    channels::ChannelSnapshot,
+    connection::{
+        default_auto_reconnect, default_fallback_encoding, default_flood_burst,
+        default_flood_refill_ms, default_ping_interval_secs, default_ping_timeout_secs,
+        default_rejoin_delay_secs, ClientCertificate, ConnectionConfig, ConnectionState,
+    },
+    ctcp::CtcpConfig,
+    encoding::Encoding,
+    isupport::ServerInfo,
+    messages::ChatMessage,
+    outgoing::OutgoingError,
+    sasl::AuthMode,
+    state::AppState,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct ConnectArgs {
@@ -14,6 +31,22 @@ pub struct ConnectArgs {
     pub password: Option<String>,
     #[serde(default)]
     pub auto_join: Vec<String>,
+    /// Settings left out by the caller keep their saved values.
+    pub requested_caps: Option<Vec<String>>,
+    pub auth: Option<AuthMode>,
+    pub sasl_required: Option<bool>,
+    pub client_cert: Option<ClientCertificate>,
+    pub auto_reconnect: Option<bool>,
+    pub ping_interval_secs: Option<u64>,
+    pub ping_timeout_secs: Option<u64>,
+    pub rejoin_on_kick: Option<bool>,
+    pub rejoin_delay_secs: Option<u64>,
+    pub ctcp: Option<CtcpConfig>,
+    pub flood_burst: Option<u32>,
+    pub flood_refill_ms: Option<u64>,
+    pub encoding: Option<Encoding>,
+    pub fallback_encoding: Option<Encoding>,
+    pub channel_encodings: Option<BTreeMap<String, Encoding>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,6 +56,12 @@ pub struct DisconnectArgs {
     pub reason: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionArgs {
+    pub connection_id: String,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JoinArgs {
@@ -30,6 +69,13 @@ pub struct JoinArgs {
     pub channel: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelArgs {
+    pub connection_id: String,
+    pub channel: String,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PartArgs {
@@ -46,6 +92,15 @@ pub struct SendMessageArgs {
     pub message: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CtcpArgs {
+    pub connection_id: String,
+    pub target: String,
+    pub command: String,
+    pub params: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ScrollbackArgs {
@@ -62,12 +117,47 @@ pub struct TopicArgs {
     pub topic: Option<String>,
 }
 
+/// Error for commands that send to the server, as `{ kind, message }` so the
+/// frontend can tell a rejected argument (`OutgoingError::kind`, or
+/// `"invalidInput"` for connect settings) from a connection problem
+/// (`"failed"`).
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    kind: &'static str,
+    message: String,
+}
+
+impl CommandError {
+    /// A connection setting that can't be sent to the server as given.
+    fn invalid_input(err: OutgoingError) -> Self {
+        Self {
+            kind: "invalidInput",
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(err: anyhow::Error) -> Self {
+        let kind = err
+            .downcast_ref::<OutgoingError>()
+            .map_or("failed", OutgoingError::kind);
+        Self {
+            kind,
+            message: err.to_string(),
+        }
+    }
+}
+
 #[tauri::command]
 pub async fn irc_connect(
     state: tauri::State<'_, AppState>,
     args: ConnectArgs,
-) -> Result<String, String> {
+) -> Result<String, CommandError> {
     let manager = state.manager();
+    let saved = state
+        .config_store()
+        .find(&args.server, args.port, &args.nickname);
     let config = ConnectionConfig {
         server: args.server,
         port: args.port,
@@ -77,14 +167,72 @@ pub async fn irc_connect(
         realname: args.realname,
         password: args.password,
         auto_join: args.auto_join,
+        requested_caps: args
+            .requested_caps
+            .or_else(|| saved.as_ref().map(|saved| saved.requested_caps.clone()))
+            .unwrap_or_else(default_requested_caps),
+        auth: args
+            .auth
+            .or_else(|| saved.as_ref().map(|saved| saved.auth.clone()))
+            .unwrap_or_default(),
+        sasl_required: args
+            .sasl_required
+            .or_else(|| saved.as_ref().map(|saved| saved.sasl_required))
+            .unwrap_or_default(),
+        client_cert: args
+            .client_cert
+            .or_else(|| saved.as_ref().map(|saved| saved.client_cert.clone()))
+            .unwrap_or_default(),
+        auto_reconnect: args
+            .auto_reconnect
+            .or_else(|| saved.as_ref().map(|saved| saved.auto_reconnect))
+            .unwrap_or_else(default_auto_reconnect),
+        ping_interval_secs: args
+            .ping_interval_secs
+            .or_else(|| saved.as_ref().map(|saved| saved.ping_interval_secs))
+            .unwrap_or_else(default_ping_interval_secs),
+        ping_timeout_secs: args
+            .ping_timeout_secs
+            .or_else(|| saved.as_ref().map(|saved| saved.ping_timeout_secs))
+            .unwrap_or_else(default_ping_timeout_secs),
+        rejoin_on_kick: args
+            .rejoin_on_kick
+            .or_else(|| saved.as_ref().map(|saved| saved.rejoin_on_kick))
+            .unwrap_or_default(),
+        rejoin_delay_secs: args
+            .rejoin_delay_secs
+            .or_else(|| saved.as_ref().map(|saved| saved.rejoin_delay_secs))
+            .unwrap_or_else(default_rejoin_delay_secs),
+        ctcp: args
+            .ctcp
+            .or_else(|| saved.as_ref().map(|saved| saved.ctcp.clone()))
+            .unwrap_or_default(),
+        flood_burst: args
+            .flood_burst
+            .or_else(|| saved.as_ref().map(|saved| saved.flood_burst))
+            .unwrap_or_else(default_flood_burst),
+        flood_refill_ms: args
+            .flood_refill_ms
+            .or_else(|| saved.as_ref().map(|saved| saved.flood_refill_ms))
+            .unwrap_or_else(default_flood_refill_ms),
+        encoding: args
+            .encoding
+            .or_else(|| saved.as_ref().map(|saved| saved.encoding))
+            .unwrap_or_default(),
+        fallback_encoding: args
+            .fallback_encoding
+            .or_else(|| saved.as_ref().map(|saved| saved.fallback_encoding))
+            .unwrap_or_else(default_fallback_encoding),
+        channel_encodings: args
+            .channel_encodings
+            .or_else(|| saved.as_ref().map(|saved| saved.channel_encodings.clone()))
+            .unwrap_or_default(),
     };
-    state
-        .config_store()
-        .upsert(&config)
-        .map_err(|e| e.to_string())?;
+    config.validate().map_err(CommandError::invalid_input)?;
+    state.config_store().upsert(&config)?;
     match manager.find_by_config(&config) {
         Some(existing) => Ok(existing.id().to_string()),
-        None => manager.connect(config).map_err(|e| e.to_string()),
+        None => Ok(manager.connect(config)?),
     }
 }
 
@@ -92,49 +240,82 @@ pub async fn irc_connect(
 pub async fn irc_disconnect(
     state: tauri::State<'_, AppState>,
     args: DisconnectArgs,
-) -> Result<(), String> {
+) -> Result<(), CommandError> {
     state
         .manager()
         .disconnect(&args.connection_id, args.reason)
-        .map_err(|e| e.to_string())
+        .map_err(CommandError::from)
 }
 
 #[tauri::command]
-pub async fn irc_join(state: tauri::State<'_, AppState>, args: JoinArgs) -> Result<(), String> {
+pub async fn irc_join(
+    state: tauri::State<'_, AppState>,
+    args: JoinArgs,
+) -> Result<(), CommandError> {
     state
         .manager()
         .join(&args.connection_id, &args.channel)
-        .map_err(|e| e.to_string())
+        .map_err(CommandError::from)
 }
 
 #[tauri::command]
-pub async fn irc_part(state: tauri::State<'_, AppState>, args: PartArgs) -> Result<(), String> {
+pub async fn irc_part(
+    state: tauri::State<'_, AppState>,
+    args: PartArgs,
+) -> Result<(), CommandError> {
     state
         .manager()
         .part(&args.connection_id, &args.channel, args.reason)
-        .map_err(|e| e.to_string())
+        .map_err(CommandError::from)
 }
 
 #[tauri::command]
 pub async fn irc_send_message(
     state: tauri::State<'_, AppState>,
     args: SendMessageArgs,
-) -> Result<(), String> {
+) -> Result<(), CommandError> {
     state
         .manager()
         .privmsg(&args.connection_id, &args.target, &args.message)
-        .map_err(|e| e.to_string())
+        .map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn irc_send_action(
+    state: tauri::State<'_, AppState>,
+    args: SendMessageArgs,
+) -> Result<(), CommandError> {
+    state
+        .manager()
+        .action(&args.connection_id, &args.target, &args.message)
+        .map_err(CommandError::from)
+}
+
+#[tauri::command]
+pub async fn irc_ctcp(
+    state: tauri::State<'_, AppState>,
+    args: CtcpArgs,
+) -> Result<(), CommandError> {
+    state
+        .manager()
+        .ctcp(
+            &args.connection_id,
+            &args.target,
+            &args.command,
+            args.params,
+        )
+        .map_err(CommandError::from)
 }
 
 #[tauri::command]
 pub async fn irc_set_topic(
     state: tauri::State<'_, AppState>,
     args: TopicArgs,
-) -> Result<(), String> {
+) -> Result<(), CommandError> {
     state
         .manager()
         .set_topic(&args.connection_id, &args.channel, args.topic)
-        .map_err(|e| e.to_string())
+        .map_err(CommandError::from)
 }
 
 #[tauri::command]
@@ -142,16 +323,21 @@ pub async fn irc_scrollback(
     state: tauri::State<'_, AppState>,
     args: ScrollbackArgs,
 ) -> Result<Vec<ChatMessage>, String> {
-    let storage_key = state
+    let (storage_key, casemapping) = state
         .manager()
         .get(&args.connection_id)
-        .map(|handle| handle.storage_key().to_string())
+        .map(|handle| {
+            (
+                handle.storage_key().to_string(),
+                handle.server_info().casemapping,
+            )
+        })
         .ok_or_else(|| "connection not found".to_string())?;
 
     state
         .manager()
         .scrollback()
-        .read_last(storage_key.as_str(), &args.target, args.limit)
+        .read_last(storage_key.as_str(), casemapping, &args.target, args.limit)
         .await
         .map_err(|e| e.to_string())
 }
@@ -169,3 +355,51 @@ pub async fn irc_saved_connections(
 ) -> Result<Vec<ConnectionConfig>, String> {
     Ok(state.config_store().list())
 }
+
+#[tauri::command]
+pub async fn irc_capabilities(
+    state: tauri::State<'_, AppState>,
+    args: ConnectionArgs,
+) -> Result<Vec<String>, String> {
+    state
+        .manager()
+        .get(&args.connection_id)
+        .map(|handle| handle.capabilities())
+        .ok_or_else(|| "connection not found".to_string())
+}
+
+#[tauri::command]
+pub async fn irc_server_info(
+    state: tauri::State<'_, AppState>,
+    args: ConnectionArgs,
+) -> Result<ServerInfo, String> {
+    state
+        .manager()
+        .get(&args.connection_id)
+        .map(|handle| handle.server_info())
+        .ok_or_else(|| "connection not found".to_string())
+}
+
+#[tauri::command]
+pub async fn irc_connection_state(
+    state: tauri::State<'_, AppState>,
+    args: ConnectionArgs,
+) -> Result<ConnectionState, String> {
+    state
+        .manager()
+        .get(&args.connection_id)
+        .map(|handle| handle.state())
+        .ok_or_else(|| "connection not found".to_string())
+}
+
+#[tauri::command]
+pub async fn irc_channel_state(
+    state: tauri::State<'_, AppState>,
+    args: ChannelArgs,
+) -> Result<Option<ChannelSnapshot>, String> {
+    state
+        .manager()
+        .get(&args.connection_id)
+        .map(|handle| handle.channel(&args.channel))
+        .ok_or_else(|| "connection not found".to_string())
+}
@@ -36,6 +36,16 @@ impl ConfigStore {
         self.connections.read().clone()
     }
 
+    pub fn find(&self, server: &str, port: u16, nickname: &str) -> Option<ConnectionConfig> {
+        self.connections
+            .read()
+            .iter()
+            .find(|existing| {
+                existing.server == server && existing.port == port && existing.nickname == nickname
+            })
+            .cloned()
+    }
+
     pub fn upsert(&self, config: &ConnectionConfig) -> anyhow::Result<()> {
         let mut guard = self.connections.write();
         if let Some(existing) = guard.iter_mut().find(|existing| {
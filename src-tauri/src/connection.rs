@@ -1,10 +1,17 @@
-use std::{collections::HashMap, sync::Arc, time::SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::{anyhow, Context};
-use parking_lot::Mutex;
+use chrono::DateTime;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncBufReadExt, BufReader},
     net::TcpStream,
     select,
     sync::mpsc,
@@ -13,11 +20,28 @@ use tokio_native_tls::native_tls;
 use uuid::Uuid;
 
 use crate::{
+    capabilities::{default_requested_caps, CapNegotiator},
+    channels::{format_mode_changes, parse_mode_changes, ChannelSnapshot, Channels},
+    ctcp::{self, CtcpConfig, CtcpResponder},
+    encoding::{self, Encoding},
+    isupport::{prefix_mode_name, CaseMapping, ServerInfo},
     messages::{ChannelUserInfo, ChatMessage, IrcEvent, MessageKind},
+    outgoing::{self, LineBuilder, OutgoingError},
+    parser::{Message, Prefix},
+    sasl::{AuthMode, SaslSession},
+    send_queue::SendQueue,
     storage::ScrollbackStore,
 };
 use tauri::Emitter;
 
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(2);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+/// How long a session has to stay registered before the reconnect backoff
+/// starts over, so a server that welcomes and then drops us still backs off.
+const STABLE_SESSION: Duration = Duration::from_secs(60);
+/// How often the connection loop checks whether a PING is due or overdue.
+const PING_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ConnectionConfig {
@@ -29,12 +53,142 @@ pub struct ConnectionConfig {
     pub realname: Option<String>,
     pub password: Option<String>,
     pub auto_join: Vec<String>,
+    #[serde(default = "default_requested_caps")]
+    pub requested_caps: Vec<String>,
+    #[serde(default)]
+    pub auth: AuthMode,
+    /// Abort the connection instead of continuing unauthenticated when SASL
+    /// fails or is unavailable.
+    #[serde(default)]
+    pub sasl_required: bool,
+    /// TLS client certificate presented to the server, e.g. for CertFP and
+    /// SASL EXTERNAL.
+    #[serde(default)]
+    pub client_cert: ClientCertificate,
+    /// Reconnect with backoff when the link drops.
+    #[serde(default = "default_auto_reconnect")]
+    pub auto_reconnect: bool,
+    /// Seconds between client-initiated PINGs used for lag measurement.
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// Seconds without a PONG after which the link is considered dead. Also
+    /// how long the server gets to welcome us after we connect.
+    #[serde(default = "default_ping_timeout_secs")]
+    pub ping_timeout_secs: u64,
+    /// Rejoin a channel we were kicked from after `rejoin_delay_secs`.
+    #[serde(default)]
+    pub rejoin_on_kick: bool,
+    #[serde(default = "default_rejoin_delay_secs")]
+    pub rejoin_delay_secs: u64,
+    #[serde(default)]
+    pub ctcp: CtcpConfig,
+    /// Lines we may send back to back before flood control paces us.
+    #[serde(default = "default_flood_burst")]
+    pub flood_burst: u32,
+    /// Milliseconds per line once the burst is used up.
+    #[serde(default = "default_flood_refill_ms")]
+    pub flood_refill_ms: u64,
+    /// Charset we send in.
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Charset for incoming lines that aren't valid UTF-8.
+    #[serde(default = "default_fallback_encoding")]
+    pub fallback_encoding: Encoding,
+    /// Per-channel overrides of `fallback_encoding`.
+    #[serde(default)]
+    pub channel_encodings: BTreeMap<String, Encoding>,
+}
+
+pub fn default_auto_reconnect() -> bool {
+    true
+}
+
+pub fn default_ping_interval_secs() -> u64 {
+    60
+}
+
+pub fn default_ping_timeout_secs() -> u64 {
+    120
+}
+
+pub fn default_rejoin_delay_secs() -> u64 {
+    5
+}
+
+pub fn default_flood_burst() -> u32 {
+    5
+}
+
+pub fn default_flood_refill_ms() -> u64 {
+    2000
+}
+
+pub fn default_fallback_encoding() -> Encoding {
+    Encoding::Windows1252
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(
+    tag = "format",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ClientCertificate {
+    #[default]
+    None,
+    /// A PKCS#12 bundle holding both the certificate and its private key.
+    Pkcs12 {
+        path: PathBuf,
+        /// Never serialized, so it stays out of `connections.json` and isn't
+        /// echoed back to the frontend; it has to be supplied on each connect.
+        #[serde(default, skip_serializing)]
+        password: Option<String>,
+    },
+    /// A PEM certificate (chain) with a PKCS#8 PEM private key.
+    Pem {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+}
+
+impl ClientCertificate {
+    pub fn is_configured(&self) -> bool {
+        !matches!(self, ClientCertificate::None)
+    }
 }
 
 impl ConnectionConfig {
     pub fn storage_key(&self) -> String {
         format!("{}:{}:{}", self.server, self.port, self.nickname)
     }
+
+    /// Rejects settings that would put a malformed line on the wire. Checked
+    /// against the default `ServerInfo`, since the server's limits aren't
+    /// known until it sends 005.
+    pub fn validate(&self) -> Result<(), OutgoingError> {
+        let server = ServerInfo::default();
+        if self.server.trim().is_empty() {
+            return Err(OutgoingError::Empty("server"));
+        }
+        outgoing::check_nick(&server, &self.nickname)?;
+        if let Some(username) = &self.username {
+            LineBuilder::new("USER")
+                .param("username", username)
+                .build()?;
+        }
+        if let Some(realname) = &self.realname {
+            outgoing::check_text("realname", realname)?;
+        }
+        if let Some(password) = &self.password {
+            LineBuilder::new("PASS")
+                .param("password", password)
+                .build()?;
+        }
+        for channel in &self.auto_join {
+            outgoing::check_channel(&server, channel)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -48,10 +202,21 @@ pub enum ConnectionCommand {
         target: String,
         message: String,
     },
+    /// `/me`: sent as a CTCP ACTION.
+    Action {
+        target: String,
+        message: String,
+    },
     Topic {
         channel: String,
         topic: Option<String>,
     },
+    /// A CTCP query such as `VERSION`; `PING` without params sends our clock.
+    Ctcp {
+        target: String,
+        command: String,
+        params: Option<String>,
+    },
     Quit {
         reason: Option<String>,
     },
@@ -64,10 +229,10 @@ pub struct ConnectionHandle {
 
 struct ConnectionInner {
     id: String,
-    #[allow(dead_code)]
     config: ConnectionConfig,
     storage_key: String,
     sender: mpsc::UnboundedSender<ConnectionCommand>,
+    shared: Arc<Mutex<SharedState>>,
     #[allow(dead_code)]
     task: tauri::async_runtime::JoinHandle<()>,
 }
@@ -92,6 +257,238 @@ impl ConnectionHandle {
         self.send_command(ConnectionCommand::Quit { reason })
     }
 
+    /// Whether the connection task has stopped for good.
+    pub fn is_closed(&self) -> bool {
+        self.inner.sender.is_closed()
+    }
+
+    pub fn capabilities(&self) -> Vec<String> {
+        self.inner.shared.lock().capabilities.clone()
+    }
+
+    pub fn server_info(&self) -> ServerInfo {
+        self.inner.shared.lock().server.clone()
+    }
+
+    pub fn channel(&self, channel: &str) -> Option<ChannelSnapshot> {
+        let shared = self.inner.shared.lock();
+        shared.channels.snapshot(&shared.server, channel)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        let shared = self.inner.shared.lock();
+        ConnectionState {
+            connection_id: self.inner.id.clone(),
+            server: self.inner.config.server.clone(),
+            nickname: shared.nickname.clone(),
+            connected: shared.registered,
+            capabilities: shared.capabilities.clone(),
+            server_info: shared.server.clone(),
+            channels: shared.channels.snapshots(&shared.server),
+        }
+    }
+}
+
+/// Everything the UI needs to rebuild a connection, e.g. after a reload.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionState {
+    pub connection_id: String,
+    pub server: String,
+    pub nickname: String,
+    /// Registered with the server right now, rather than waiting to reconnect.
+    pub connected: bool,
+    pub capabilities: Vec<String>,
+    pub server_info: ServerInfo,
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+/// Connection state published by the connection task for the rest of the app.
+#[derive(Debug, Default)]
+struct SharedState {
+    /// Our nick as the server currently knows it.
+    nickname: String,
+    registered: bool,
+    capabilities: Vec<String>,
+    server: ServerInfo,
+    /// Channels we are currently in, and who is in them.
+    channels: Channels,
+}
+
+/// Protocol state owned by the connection task.
+struct Session {
+    id: String,
+    storage_key: String,
+    config: ConnectionConfig,
+    events: EventSink,
+    scrollback: ScrollbackStore,
+    caps: CapNegotiator,
+    sasl: Option<SaslSession>,
+    sasl_done: bool,
+    /// Set by the line handler when the connection has to be torn down.
+    close_reason: Option<SessionEnd>,
+    /// The TCP/TLS stream was established.
+    connected: bool,
+    /// The server welcomed us (001).
+    registered: bool,
+    /// When the server welcomed us.
+    registered_at: Option<Instant>,
+    /// Number of the reconnect attempt this session is, 0 for the first.
+    reconnect_attempt: u32,
+    /// Channels to join once registered.
+    rejoin: Vec<String>,
+    /// Channels we were kicked from, to rejoin at the given time.
+    kick_rejoins: Vec<(Instant, String)>,
+    /// Our nick as the server currently knows it.
+    nickname: String,
+    /// Our `user@host` as others see it, once the server has shown us.
+    userhost: Option<String>,
+    ctcp: CtcpResponder,
+    /// `NAMES` replies for channels we are not in, collected per folded
+    /// channel until `RPL_ENDOFNAMES`. Joined channels collect in `Channels`.
+    pending_names: HashMap<String, (String, Vec<ChannelUserInfo>)>,
+    server: ServerInfo,
+    ping: PingTracker,
+    shared: Arc<Mutex<SharedState>>,
+}
+
+impl Session {
+    fn new(
+        id: String,
+        storage_key: String,
+        config: ConnectionConfig,
+        events: EventSink,
+        scrollback: ScrollbackStore,
+        shared: Arc<Mutex<SharedState>>,
+    ) -> Self {
+        Self {
+            id,
+            storage_key,
+            caps: CapNegotiator::new(&wanted_caps(&config)),
+            sasl: None,
+            sasl_done: false,
+            close_reason: None,
+            connected: false,
+            registered: false,
+            registered_at: None,
+            reconnect_attempt: 0,
+            rejoin: config.auto_join.clone(),
+            kick_rejoins: Vec::new(),
+            nickname: config.nickname.clone(),
+            userhost: None,
+            ctcp: CtcpResponder::new(config.ctcp.clone()),
+            pending_names: HashMap::new(),
+            server: ServerInfo::default(),
+            ping: PingTracker::new(&config),
+            events,
+            scrollback,
+            shared,
+            config,
+        }
+    }
+
+    /// Whether the session stayed registered for `STABLE_SESSION` before
+    /// ending at `ended`.
+    fn was_stable(&self, ended: Instant) -> bool {
+        self.registered_at
+            .is_some_and(|at| ended.saturating_duration_since(at) >= STABLE_SESSION)
+    }
+
+    /// The channel state shared with `ConnectionHandle::channel`. Don't hold
+    /// the guard across an await.
+    fn channels(&self) -> MappedMutexGuard<'_, Channels> {
+        MutexGuard::map(self.shared.lock(), |shared| &mut shared.channels)
+    }
+}
+
+/// Where a session's events go.
+#[derive(Clone)]
+enum EventSink {
+    App(tauri::AppHandle),
+    /// Serializes events and drops them, so tests and the fuzz targets can
+    /// run the line handler without a Tauri app.
+    #[cfg(any(test, feature = "fuzzing"))]
+    Discard,
+}
+
+impl EventSink {
+    fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) -> tauri::Result<()> {
+        match self {
+            EventSink::App(app_handle) => app_handle.emit(event, payload),
+            #[cfg(any(test, feature = "fuzzing"))]
+            EventSink::Discard => {
+                serde_json::to_string(&payload)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Client-initiated PINGs, used to notice half-open links and to measure lag.
+struct PingTracker {
+    interval: Duration,
+    timeout: Duration,
+    last_sent: Instant,
+    outstanding: Option<(String, Instant)>,
+    /// When the server must have welcomed us by, until it has.
+    registration_deadline: Option<Instant>,
+}
+
+impl PingTracker {
+    fn new(config: &ConnectionConfig) -> Self {
+        Self {
+            interval: Duration::from_secs(config.ping_interval_secs.max(1)),
+            timeout: Duration::from_secs(config.ping_timeout_secs.max(1)),
+            last_sent: Instant::now(),
+            outstanding: None,
+            registration_deadline: None,
+        }
+    }
+
+    /// Starts the registration clock; we only PING once registered, so until
+    /// then this is what notices a server that never answers.
+    fn connected(&mut self, now: Instant) {
+        self.registration_deadline = now.checked_add(self.timeout);
+    }
+
+    fn registered(&mut self, now: Instant) {
+        self.registration_deadline = None;
+        self.last_sent = now;
+    }
+
+    fn registration_timed_out(&self, now: Instant) -> bool {
+        self.registration_deadline
+            .is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Returns the token for a new PING when one is due.
+    fn due(&mut self, now: Instant) -> Option<String> {
+        if self.outstanding.is_some() || now.duration_since(self.last_sent) < self.interval {
+            return None;
+        }
+        let token = format!("fluxchat-{}", current_timestamp());
+        self.last_sent = now;
+        self.outstanding = Some((token.clone(), now));
+        Some(token)
+    }
+
+    fn timed_out(&self, now: Instant) -> bool {
+        self.outstanding
+            .as_ref()
+            .is_some_and(|(_, sent)| now.duration_since(*sent) >= self.timeout)
+    }
+
+    /// Matches a PONG against the outstanding PING, returning the round trip.
+    fn pong(&mut self, token: &str) -> Option<Duration> {
+        match &self.outstanding {
+            Some((expected, sent)) if expected == token => {
+                let lag = sent.elapsed();
+                self.outstanding = None;
+                Some(lag)
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -146,11 +543,14 @@ impl ConnectionManager {
             .connections
             .lock()
             .values()
-            .find(|conn| conn.storage_key() == storage_key)
+            .find(|conn| conn.storage_key() == storage_key && !conn.is_closed())
             .cloned()
     }
 
     pub fn disconnect(&self, id: &str, reason: Option<String>) -> anyhow::Result<()> {
+        if let Some(reason) = &reason {
+            outgoing::check_text("quit reason", reason)?;
+        }
         if let Some(handle) = self.remove(id) {
             handle.disconnect(reason.clone())?;
             let _ = self.inner.app_handle.emit(
@@ -172,6 +572,7 @@ impl ConnectionManager {
 
     pub fn join(&self, id: &str, channel: &str) -> anyhow::Result<()> {
         if let Some(handle) = self.get(id) {
+            outgoing::check_channel(&handle.server_info(), channel)?;
             handle.send_command(ConnectionCommand::Join(channel.to_string()))?;
             Ok(())
         } else {
@@ -181,6 +582,10 @@ impl ConnectionManager {
 
     pub fn part(&self, id: &str, channel: &str, reason: Option<String>) -> anyhow::Result<()> {
         if let Some(handle) = self.get(id) {
+            outgoing::check_channel(&handle.server_info(), channel)?;
+            if let Some(reason) = &reason {
+                outgoing::check_text("part reason", reason)?;
+            }
             handle.send_command(ConnectionCommand::Part {
                 channel: channel.to_string(),
                 reason,
@@ -193,6 +598,8 @@ impl ConnectionManager {
 
     pub fn privmsg(&self, id: &str, target: &str, message: &str) -> anyhow::Result<()> {
         if let Some(handle) = self.get(id) {
+            outgoing::check_target(&handle.server_info(), target)?;
+            outgoing::check_message(message)?;
             handle.send_command(ConnectionCommand::Privmsg {
                 target: target.to_string(),
                 message: message.to_string(),
@@ -203,8 +610,51 @@ impl ConnectionManager {
         }
     }
 
+    pub fn action(&self, id: &str, target: &str, message: &str) -> anyhow::Result<()> {
+        if let Some(handle) = self.get(id) {
+            outgoing::check_target(&handle.server_info(), target)?;
+            outgoing::check_message(message)?;
+            handle.send_command(ConnectionCommand::Action {
+                target: target.to_string(),
+                message: message.to_string(),
+            })?;
+            Ok(())
+        } else {
+            Err(anyhow!("connection not found"))
+        }
+    }
+
+    pub fn ctcp(
+        &self,
+        id: &str,
+        target: &str,
+        command: &str,
+        params: Option<String>,
+    ) -> anyhow::Result<()> {
+        if let Some(handle) = self.get(id) {
+            outgoing::check_target(&handle.server_info(), target)?;
+            outgoing::check_ctcp(command, params.as_deref())?;
+            handle.send_command(ConnectionCommand::Ctcp {
+                target: target.to_string(),
+                command: command.to_ascii_uppercase(),
+                params,
+            })?;
+            Ok(())
+        } else {
+            Err(anyhow!("connection not found"))
+        }
+    }
+
     pub fn set_topic(&self, id: &str, channel: &str, topic: Option<String>) -> anyhow::Result<()> {
         if let Some(handle) = self.get(id) {
+            let server = handle.server_info();
+            outgoing::check_channel(&server, channel)?;
+            if let Some(topic) = &topic {
+                outgoing::check_text("topic", topic)?;
+                if server.topiclen.is_some_and(|len| topic.len() > len) {
+                    return Err(OutgoingError::TooLong("topic").into());
+                }
+            }
             handle.send_command(ConnectionCommand::Topic {
                 channel: channel.to_string(),
                 topic,
@@ -218,12 +668,22 @@ impl ConnectionManager {
     pub fn connect(&self, config: ConnectionConfig) -> anyhow::Result<String> {
         let id = Uuid::new_v4().to_string();
         let storage_key = config.storage_key();
+        // Drop connections whose task gave up; this one replaces them.
+        self.inner
+            .connections
+            .lock()
+            .retain(|_, conn| !(conn.storage_key() == storage_key && conn.is_closed()));
         let (tx, rx) = mpsc::unbounded_channel();
         let app_handle = self.inner.app_handle.clone();
         let scrollback = self.inner.scrollback.clone();
         let connection_id = id.clone();
         let worker_config = config.clone();
         let worker_storage_key = storage_key.clone();
+        let shared = Arc::new(Mutex::new(SharedState {
+            nickname: config.nickname.clone(),
+            ..SharedState::default()
+        }));
+        let worker_shared = shared.clone();
         let task = tauri::async_runtime::spawn(async move {
             connection_task(
                 connection_id,
@@ -231,6 +691,7 @@ impl ConnectionManager {
                 worker_config,
                 app_handle,
                 scrollback,
+                worker_shared,
                 rx,
             )
             .await;
@@ -241,6 +702,7 @@ impl ConnectionManager {
                 config,
                 storage_key,
                 sender: tx,
+                shared,
                 task,
             }),
         };
@@ -250,47 +712,206 @@ impl ConnectionManager {
     }
 }
 
+/// Why a single connection attempt ended.
+enum SessionEnd {
+    /// The user asked to quit; the supervisor stops.
+    Quit,
+    /// The link failed or the server closed it; the supervisor may reconnect.
+    Lost(String),
+    /// We gave up on purpose, e.g. required SASL failed; reconnecting would
+    /// only fail the same way, so the supervisor stops.
+    Abort(String),
+}
+
+/// Supervises a connection: runs sessions back to back, reconnecting with
+/// jittered exponential backoff and rejoining the channels that were joined
+/// when the link dropped.
 async fn connection_task(
     id: String,
     storage_key: String,
     config: ConnectionConfig,
     app_handle: tauri::AppHandle,
     scrollback: ScrollbackStore,
+    shared: Arc<Mutex<SharedState>>,
     mut command_rx: mpsc::UnboundedReceiver<ConnectionCommand>,
 ) {
+    let mut rejoin = config.auto_join.clone();
+    let mut attempt = 0;
+    loop {
+        shared.lock().channels = Channels::default();
+        let mut session = Session::new(
+            id.clone(),
+            storage_key.clone(),
+            config.clone(),
+            EventSink::App(app_handle.clone()),
+            scrollback.clone(),
+            shared.clone(),
+        );
+        session.reconnect_attempt = attempt;
+        session.rejoin = rejoin.clone();
+        let end = run_session(&mut session, &mut command_rx).await;
+        shared.lock().registered = false;
+        let (reason, abort) = match end {
+            SessionEnd::Quit => break,
+            SessionEnd::Lost(reason) => (reason, false),
+            SessionEnd::Abort(reason) => (reason, true),
+        };
+        if session.connected {
+            let _ = app_handle.emit(
+                "irc://event",
+                IrcEvent::Disconnected {
+                    connection_id: id.clone(),
+                    reason: Some(reason),
+                },
+            );
+        }
+        if abort {
+            break;
+        }
+        let casemapping = session.server.casemapping;
+        if session.registered {
+            rejoin = session.channels().names();
+        }
+        if session.was_stable(Instant::now()) {
+            attempt = 0;
+        }
+        if !config.auto_reconnect {
+            break;
+        }
+        attempt += 1;
+        let delay = reconnect_delay(attempt);
+        tracing::info!(
+            "reconnecting to {} in {delay:?} (attempt {attempt})",
+            config.server
+        );
+        let _ = app_handle.emit(
+            "irc://event",
+            IrcEvent::Reconnecting {
+                connection_id: id.clone(),
+                attempt,
+                delay_ms: delay.as_millis() as u64,
+            },
+        );
+        let wait = wait_for_reconnect(
+            &id,
+            &app_handle,
+            delay,
+            casemapping,
+            &mut command_rx,
+            &mut rejoin,
+        );
+        if !wait.await {
+            break;
+        }
+    }
+}
+
+/// Backoff before reconnect `attempt` (starting at 1): doubles from
+/// `RECONNECT_BASE_DELAY` up to `RECONNECT_MAX_DELAY`, randomised between half
+/// and the full delay so clients dropped by the same netsplit spread out.
+fn reconnect_delay(attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(16);
+    let delay = RECONNECT_BASE_DELAY
+        .saturating_mul(1 << exponent)
+        .min(RECONNECT_MAX_DELAY);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// Sleeps until the next reconnect attempt while keeping the command channel
+/// serviced. Returns `false` when the connection should stop instead.
+async fn wait_for_reconnect(
+    id: &str,
+    app_handle: &tauri::AppHandle,
+    delay: Duration,
+    casemapping: CaseMapping,
+    command_rx: &mut mpsc::UnboundedReceiver<ConnectionCommand>,
+    rejoin: &mut Vec<String>,
+) -> bool {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        select! {
+            _ = &mut sleep => return true,
+            cmd = command_rx.recv() => match cmd {
+                None => return false,
+                Some(ConnectionCommand::Quit { reason }) => {
+                    let _ = app_handle.emit(
+                        "irc://event",
+                        IrcEvent::Disconnected {
+                            connection_id: id.to_string(),
+                            reason,
+                        },
+                    );
+                    return false;
+                }
+                Some(ConnectionCommand::Join(channel)) => {
+                    if !rejoin.iter().any(|joined| casemapping.equals(joined, &channel)) {
+                        rejoin.push(channel);
+                    }
+                }
+                Some(ConnectionCommand::Part { channel, .. }) => {
+                    rejoin.retain(|joined| !casemapping.equals(joined, &channel));
+                }
+                Some(_) => {
+                    let _ = app_handle.emit(
+                        "irc://event",
+                        IrcEvent::Error {
+                            connection_id: id.to_string(),
+                            message: "not connected; waiting to reconnect".into(),
+                        },
+                    );
+                }
+            },
+        }
+    }
+}
+
+async fn run_session(
+    session: &mut Session,
+    command_rx: &mut mpsc::UnboundedReceiver<ConnectionCommand>,
+) -> SessionEnd {
+    let id = session.id.clone();
+    let config = session.config.clone();
+    let events = session.events.clone();
     let addr = format!("{}:{}", config.server, config.port);
     let stream = match connect_stream(&config).await {
         Ok(stream) => stream,
         Err(err) => {
             tracing::error!("failed to connect to {addr}: {err}");
-            let _ = app_handle.emit(
+            let _ = events.emit(
                 "irc://event",
                 IrcEvent::Error {
                     connection_id: id.clone(),
                     message: format!("failed to connect: {err}"),
                 },
             );
-            return;
+            return SessionEnd::Lost(format!("failed to connect: {err}"));
         }
     };
+    session.connected = true;
+    session.ping.connected(Instant::now());
 
     let (reader, writer) = stream;
-    let mut writer = BufWriter::new(writer);
-    let mut disconnected = false;
+    let mut writer = SendQueue::new(
+        writer,
+        config.encoding,
+        config.flood_burst,
+        Duration::from_millis(config.flood_refill_ms),
+    );
 
     if let Err(err) = perform_handshake(&config, &mut writer).await {
         tracing::error!("handshake failed: {err}");
-        let _ = app_handle.emit(
+        let _ = events.emit(
             "irc://event",
             IrcEvent::Error {
                 connection_id: id.clone(),
                 message: format!("handshake failed: {err}"),
             },
         );
-        return;
+        return SessionEnd::Lost(format!("handshake failed: {err}"));
     }
 
-    let _ = app_handle.emit(
+    let _ = events.emit(
         "irc://event",
         IrcEvent::Connected {
             connection_id: id.clone(),
@@ -300,127 +921,174 @@ async fn connection_task(
         },
     );
 
-    let mut lines = BufReader::new(reader).lines();
+    let mut reader = BufReader::new(reader);
+    // Raw bytes, since one line in another charset mustn't end the session.
+    // `read_until` appends to this across cancelled reads, so it is only
+    // cleared once a full line has been handled.
+    let mut line_buf = Vec::new();
+    let mut ping_check = tokio::time::interval(PING_CHECK_INTERVAL);
+    let mut reported_pending = 0;
     loop {
+        let pending = writer.pending();
+        if pending != reported_pending {
+            reported_pending = pending;
+            let _ = events.emit(
+                "irc://event",
+                IrcEvent::SendQueue {
+                    connection_id: id.clone(),
+                    pending,
+                },
+            );
+        }
+        let next_kick_rejoin = session.kick_rejoins.iter().map(|(at, _)| *at).min();
+        let next_send = writer.next_send();
         select! {
-            maybe_line = lines.next_line() => {
-                match maybe_line {
-                    Ok(Some(line)) => {
-                        if let Err(err) = handle_line(
-                            &id,
-                            storage_key.as_str(),
-                            &config,
-                            &app_handle,
-                            &scrollback,
-                            &mut writer,
-                            &line,
-                        )
-                        .await
-                        {
+            read = reader.read_until(b'\n', &mut line_buf) => {
+                match read {
+                    Ok(n) if n > 0 => {
+                        let line = decode_incoming(session, &line_buf);
+                        line_buf.clear();
+                        if let Err(err) = handle_line(session, &mut writer, &line).await {
                             tracing::error!("failed to handle line: {err}");
                         }
+                        if let Some(end) = session.close_reason.take() {
+                            let _ = writer.flush().await;
+                            return end;
+                        }
                     }
-                    Ok(None) => {
+                    Ok(_) => {
                         tracing::info!("connection closed");
-                        let _ = app_handle.emit(
-                            "irc://event",
-                            IrcEvent::Disconnected {
-                                connection_id: id.clone(),
-                                reason: Some("connection closed".into()),
-                            },
-                        );
-                        disconnected = true;
-                        break;
+                        return SessionEnd::Lost("connection closed".into());
                     }
                     Err(err) => {
                         tracing::error!("io error: {err}");
-                        let _ = app_handle.emit(
-                            "irc://event",
-                            IrcEvent::Disconnected {
-                                connection_id: id.clone(),
-                                reason: Some(format!("read error: {err}")),
-                            },
-                        );
-                        disconnected = true;
-                        break;
+                        return SessionEnd::Lost(format!("read error: {err}"));
+                    }
+                }
+            }
+            _ = ping_check.tick() => {
+                let now = Instant::now();
+                if session.ping.registration_timed_out(now) {
+                    let secs = session.ping.timeout.as_secs();
+                    tracing::warn!("{addr} didn't register us in {secs}s");
+                    return SessionEnd::Lost(format!("registration timeout: {secs} seconds"));
+                }
+                if session.ping.timed_out(now) {
+                    let secs = session.ping.timeout.as_secs();
+                    tracing::warn!("no PONG from {addr} in {secs}s");
+                    return SessionEnd::Lost(format!("ping timeout: {secs} seconds"));
+                }
+                if session.registered {
+                    if let Some(token) = session.ping.due(now) {
+                        // Ahead of the queue, or we'd measure our own backlog as lag.
+                        let _ = write_priority(&mut writer, &format!("PING :{token}")).await;
                     }
                 }
             }
+            _ = tokio::time::sleep_until(next_kick_rejoin.unwrap_or_else(Instant::now).into()),
+                if next_kick_rejoin.is_some() =>
+            {
+                let now = Instant::now();
+                let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut session.kick_rejoins)
+                    .into_iter()
+                    .partition(|(at, _)| *at <= now);
+                session.kick_rejoins = pending;
+                for (_, channel) in due {
+                    let _ = write_built(&mut writer, join_line(&session.server, &channel)).await;
+                }
+            }
+            _ = tokio::time::sleep_until(next_send.unwrap_or_else(Instant::now).into()),
+                if next_send.is_some() =>
+            {
+                if let Err(err) = writer.flush_ready().await {
+                    tracing::error!("io error: {err}");
+                    return SessionEnd::Lost(format!("write error: {err}"));
+                }
+            }
             Some(cmd) = command_rx.recv() => {
                 match cmd {
                     ConnectionCommand::Join(channel) => {
-                        let _ = write_line(&mut writer, &format!("JOIN {channel}")).await;
+                        let line = LineBuilder::new("JOIN").param("channel", &channel).build();
+                        let _ = write_built(&mut writer, line).await;
                     }
                     ConnectionCommand::Part { channel, reason } => {
-                        if let Some(reason) = reason {
-                            let _ = write_line(&mut writer, &format!("PART {channel} :{reason}")).await;
-                        } else {
-                            let _ = write_line(&mut writer, &format!("PART {channel}")).await;
-                        }
+                        let casemapping = session.server.casemapping;
+                        session
+                            .kick_rejoins
+                            .retain(|(_, kicked)| !casemapping.equals(kicked, &channel));
+                        let line = LineBuilder::new("PART")
+                            .param("channel", &channel)
+                            .trailing_opt("part reason", reason.as_deref())
+                            .build();
+                        let _ = write_built(&mut writer, line).await;
                     }
                     ConnectionCommand::Privmsg { target, message } => {
-                        let _ = write_line(&mut writer, &format!("PRIVMSG {target} :{message}")).await;
-                        let echo = ChatMessage {
+                        send_message(session, &mut writer, target, message, MessageKind::Privmsg).await;
+                    }
+                    ConnectionCommand::Action { target, message } => {
+                        send_message(session, &mut writer, target, message, MessageKind::Action).await;
+                    }
+                    ConnectionCommand::Topic { channel, topic } => {
+                        let line = LineBuilder::new("TOPIC")
+                            .param("channel", &channel)
+                            .trailing_opt("topic", topic.as_deref())
+                            .build();
+                        let _ = write_built(&mut writer, line).await;
+                    }
+                    ConnectionCommand::Ctcp { target, command, params } => {
+                        let params = match params {
+                            Some(params) => params,
+                            None if command == "PING" => current_timestamp().to_string(),
+                            None => String::new(),
+                        };
+                        let line = LineBuilder::new("PRIVMSG")
+                            .param("target", &target)
+                            .trailing("CTCP params", &ctcp::encode(&command, &params))
+                            .build();
+                        if write_built(&mut writer, line).await.is_err() {
+                            continue;
+                        }
+                        let info = ChatMessage {
                             connection_id: id.clone(),
-                            target: target.clone(),
-                            sender: Some(config.nickname.clone()),
-                            message,
-                            kind: MessageKind::Privmsg,
+                            target: "*server".into(),
+                            sender: None,
+                            message: format!("Sent CTCP {command} to {target}"),
+                            kind: MessageKind::Info,
                             timestamp: current_timestamp(),
                             metadata: None,
                         };
-                        scrollback.append(storage_key.as_str(), &echo).await.ok();
-                        let _ = app_handle.emit("irc://event", IrcEvent::Message { data: echo });
-                    }
-                    ConnectionCommand::Topic { channel, topic } => {
-                        match topic {
-                            Some(topic) => {
-                                let _ = write_line(&mut writer, &format!("TOPIC {channel} :{topic}")).await;
-                            }
-                            None => {
-                                let _ = write_line(&mut writer, &format!("TOPIC {channel}" )).await;
-                            }
-                        }
+                        let _ = events.emit("irc://event", IrcEvent::Message { data: info });
                     }
                     ConnectionCommand::Quit { reason } => {
-                        if let Some(ref reason_text) = reason {
-                            let _ = write_line(&mut writer, &format!("QUIT :{reason_text}")).await;
-                        } else {
-                            let _ = write_line(&mut writer, "QUIT").await;
+                        let line = LineBuilder::new("QUIT")
+                            .trailing_opt("quit reason", reason.as_deref())
+                            .build();
+                        if let Ok(line) = line {
+                            let _ = write_priority(&mut writer, &line).await;
                         }
                         let _ = writer.flush().await;
-                        let _ = app_handle.emit(
+                        let _ = events.emit(
                             "irc://event",
                             IrcEvent::Disconnected {
                                 connection_id: id.clone(),
                                 reason: reason.clone(),
                             },
                         );
-                        disconnected = true;
-                        break;
+                        return SessionEnd::Quit;
                     }
                 }
             }
             else => {
                 // both streams closed
-                break;
+                return SessionEnd::Quit;
             }
         }
     }
-
-    if !disconnected {
-        let _ = app_handle.emit(
-            "irc://event",
-            IrcEvent::Disconnected {
-                connection_id: id,
-                reason: Some("connection closed".into()),
-            },
-        );
-    }
 }
 
 type AnyReader = Box<dyn tokio::io::AsyncRead + Send + Unpin>;
 type AnyWriter = Box<dyn tokio::io::AsyncWrite + Send + Unpin>;
+type Outbox = SendQueue<AnyWriter>;
 
 async fn connect_stream(config: &ConnectionConfig) -> anyhow::Result<(AnyReader, AnyWriter)> {
     let addr = format!("{}:{}", config.server, config.port);
@@ -429,7 +1097,11 @@ async fn connect_stream(config: &ConnectionConfig) -> anyhow::Result<(AnyReader,
         .with_context(|| format!("failed to connect to {addr}"))?;
     stream.set_nodelay(true)?;
     if config.use_tls {
-        let connector = native_tls::TlsConnector::new()?;
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(identity) = load_identity(&config.client_cert).await? {
+            builder.identity(identity);
+        }
+        let connector = builder.build()?;
         let connector = tokio_native_tls::TlsConnector::from(connector);
         let tls_stream = connector
             .connect(&config.server, stream)
@@ -438,15 +1110,44 @@ async fn connect_stream(config: &ConnectionConfig) -> anyhow::Result<(AnyReader,
         let (read_half, write_half) = tokio::io::split(tls_stream);
         Ok((Box::new(read_half), Box::new(write_half)))
     } else {
+        if config.client_cert.is_configured() {
+            tracing::warn!("client certificate ignored for plaintext connection to {addr}");
+        }
         let (read_half, write_half) = stream.into_split();
         Ok((Box::new(read_half), Box::new(write_half)))
     }
 }
 
-async fn perform_handshake(
-    config: &ConnectionConfig,
-    writer: &mut BufWriter<AnyWriter>,
-) -> anyhow::Result<()> {
+async fn load_identity(cert: &ClientCertificate) -> anyhow::Result<Option<native_tls::Identity>> {
+    let identity = match cert {
+        ClientCertificate::None => return Ok(None),
+        ClientCertificate::Pkcs12 { path, password } => {
+            let der = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("failed to read client certificate {}", path.display()))?;
+            native_tls::Identity::from_pkcs12(&der, password.as_deref().unwrap_or_default())
+                .with_context(|| format!("invalid PKCS#12 certificate {}", path.display()))?
+        }
+        ClientCertificate::Pem {
+            cert_path,
+            key_path,
+        } => {
+            let pem = tokio::fs::read(cert_path).await.with_context(|| {
+                format!("failed to read client certificate {}", cert_path.display())
+            })?;
+            let key = tokio::fs::read(key_path)
+                .await
+                .with_context(|| format!("failed to read client key {}", key_path.display()))?;
+            native_tls::Identity::from_pkcs8(&pem, &key)
+                .with_context(|| format!("invalid PEM certificate {}", cert_path.display()))?
+        }
+    };
+    Ok(Some(identity))
+}
+
+async fn perform_handshake(config: &ConnectionConfig, writer: &mut Outbox) -> anyhow::Result<()> {
+    // Registration is held by the server until we send CAP END.
+    write_line(writer, "CAP LS 302").await?;
     if let Some(pass) = &config.password {
         write_line(writer, &format!("PASS {pass}")).await?;
     }
@@ -463,86 +1164,430 @@ async fn perform_handshake(
     Ok(())
 }
 
-async fn write_line(writer: &mut BufWriter<AnyWriter>, line: &str) -> anyhow::Result<()> {
-    writer
-        .write_all(line.as_bytes())
-        .await
-        .context("failed to write line")?;
-    writer
-        .write_all(b"\r\n")
-        .await
-        .context("failed to write line ending")?;
-    writer.flush().await.context("failed to flush writer")?;
+fn wanted_caps(config: &ConnectionConfig) -> Vec<String> {
+    let mut caps = config.requested_caps.clone();
+    if config.auth.uses_sasl() && !caps.iter().any(|cap| cap.eq_ignore_ascii_case("sasl")) {
+        caps.push("sasl".to_string());
+    }
+    caps
+}
+
+/// Called once initial CAP negotiation has settled: authenticates first when
+/// SASL is configured, otherwise lets registration continue.
+async fn begin_registration(session: &mut Session, writer: &mut Outbox) -> anyhow::Result<()> {
+    if !session.config.auth.uses_sasl() {
+        return end_cap_negotiation(session, writer).await;
+    }
+    if !session.caps.is_enabled("sasl") {
+        return sasl_failed(session, writer, "server does not support SASL").await;
+    }
+    if matches!(session.config.auth, AuthMode::SaslExternal)
+        && !(session.config.use_tls && session.config.client_cert.is_configured())
+    {
+        let reason = "SASL EXTERNAL needs a TLS connection with a client certificate";
+        return sasl_failed(session, writer, reason).await;
+    }
+    match SaslSession::start(&session.config.auth, session.caps.value("sasl")) {
+        Ok(sasl) => {
+            write_line(writer, &sasl.initial_line()).await?;
+            session.sasl = Some(sasl);
+            Ok(())
+        }
+        Err(err) => sasl_failed(session, writer, &err.to_string()).await,
+    }
+}
+
+async fn end_cap_negotiation(session: &mut Session, writer: &mut Outbox) -> anyhow::Result<()> {
+    if session.caps.is_negotiating() {
+        write_line(writer, "CAP END").await?;
+        session.caps.end();
+    }
     Ok(())
 }
 
-async fn handle_line(
-    connection_id: &str,
-    storage_key: &str,
-    config: &ConnectionConfig,
-    app_handle: &tauri::AppHandle,
-    scrollback: &ScrollbackStore,
-    writer: &mut BufWriter<AnyWriter>,
-    line: &str,
+async fn sasl_failed(
+    session: &mut Session,
+    writer: &mut Outbox,
+    reason: &str,
 ) -> anyhow::Result<()> {
-    let parsed = parse_message(line);
-    match parsed.command.as_str() {
-        "PING" => {
-            if let Some(arg) = parsed
-                .params
-                .get(0)
-                .cloned()
-                .or_else(|| parsed.trailing.clone())
+    session.sasl = None;
+    session.sasl_done = true;
+    let message = format!("SASL authentication failed: {reason}");
+    let _ = session.events.emit(
+        "irc://event",
+        IrcEvent::Error {
+            connection_id: session.id.clone(),
+            message: message.clone(),
+        },
+    );
+    if session.config.sasl_required {
+        write_priority(writer, "QUIT :SASL authentication failed").await?;
+        session.close_reason = Some(SessionEnd::Abort(message));
+        return Ok(());
+    }
+    end_cap_negotiation(session, writer).await
+}
+
+/// Decodes one raw line: UTF-8 when valid, otherwise the fallback for the
+/// channel it is addressed to, else the connection's.
+fn decode_incoming(session: &Session, bytes: &[u8]) -> String {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+    let config = &session.config;
+    let mut fallback = config.fallback_encoding;
+    if std::str::from_utf8(bytes).is_err() && !config.channel_encodings.is_empty() {
+        // Latin-1 maps each byte to one char, so the target survives intact.
+        let line = Encoding::Latin1.decode(bytes);
+        if let Some(target) = Message::parse(&line).param(0) {
+            let casemapping = session.server.casemapping;
+            if let Some((_, encoding)) = config
+                .channel_encodings
+                .iter()
+                .find(|(channel, _)| casemapping.equals(channel, target))
             {
-                let _ = write_line(writer, &format!("PONG :{arg}")).await;
+                fallback = *encoding;
             }
         }
-        "001" => {
-            // Welcome
-            let _ = app_handle.emit(
-                "irc://event",
-                IrcEvent::Connected {
+    }
+    encoding::decode_line(bytes, fallback)
+}
+
+/// Queues a line behind the flood limit once registered.
+async fn write_line(writer: &mut Outbox, line: &str) -> anyhow::Result<()> {
+    check_line(line)?;
+    writer.send(line).await.context("failed to write line")
+}
+
+/// Writes a line ahead of the queue, for replies the server is waiting on.
+async fn write_priority(writer: &mut Outbox, line: &str) -> anyhow::Result<()> {
+    check_line(line)?;
+    writer.send_now(line).await.context("failed to write line")
+}
+
+fn check_line(line: &str) -> anyhow::Result<()> {
+    // Anything past a line break would reach the server as a command of its own.
+    if line.contains(['\r', '\n', '\0']) {
+        return Err(anyhow!("refusing to send a line containing CR, LF or NUL"));
+    }
+    Ok(())
+}
+
+/// Sends a PRIVMSG (wrapped in CTCP ACTION for `MessageKind::Action`) and
+/// echoes it locally, since servers don't send our own messages back. Text
+/// is split into as many lines as needed to survive the server's relay.
+async fn send_message(
+    session: &mut Session,
+    writer: &mut Outbox,
+    target: String,
+    message: String,
+    kind: MessageKind,
+) {
+    let overhead = match kind {
+        MessageKind::Action => ctcp::encode("ACTION", "").len() + " ".len(),
+        _ => 0,
+    };
+    let budget = outgoing::privmsg_budget(
+        session.server.linelen,
+        &session.nickname,
+        session.userhost.as_deref(),
+        &target,
+        overhead,
+    );
+    let casemapping = session.server.casemapping;
+    if !session.server.is_channel(&target) {
+        session.channels().add_query(casemapping, &target);
+    }
+    for line in outgoing::split_message(&message, budget) {
+        let body = match kind {
+            MessageKind::Action => ctcp::encode("ACTION", &line),
+            _ => line.clone(),
+        };
+        let privmsg = LineBuilder::new("PRIVMSG")
+            .param("target", &target)
+            .trailing("message", &body)
+            .build();
+        if write_built(writer, privmsg).await.is_err() {
+            return;
+        }
+        let echo = ChatMessage {
+            connection_id: session.id.clone(),
+            target: target.clone(),
+            sender: Some(session.nickname.clone()),
+            message: line,
+            kind: kind.clone(),
+            timestamp: current_timestamp(),
+            metadata: None,
+        };
+        session
+            .scrollback
+            .append(&session.storage_key, casemapping, &echo)
+            .await
+            .ok();
+        let _ = session
+            .events
+            .emit("irc://event", IrcEvent::Message { data: echo });
+    }
+}
+
+/// Writes a line from `LineBuilder`, logging (rather than sending) one that
+/// failed validation.
+async fn write_built(
+    writer: &mut Outbox,
+    line: Result<String, OutgoingError>,
+) -> anyhow::Result<()> {
+    match line {
+        Ok(line) => write_line(writer, &line).await,
+        Err(err) => {
+            tracing::warn!("not sending command: {err}");
+            Err(err.into())
+        }
+    }
+}
+
+/// A `JOIN` we send on our own, for auto-join and rejoins, checked like one
+/// the user typed.
+fn join_line(server: &ServerInfo, channel: &str) -> Result<String, OutgoingError> {
+    outgoing::check_channel(server, channel)?;
+    LineBuilder::new("JOIN").param("channel", channel).build()
+}
+
+async fn handle_line(session: &mut Session, writer: &mut Outbox, line: &str) -> anyhow::Result<()> {
+    let connection_id = session.id.as_str();
+    let storage_key = session.storage_key.as_str();
+    let events = &session.events;
+    let scrollback = &session.scrollback;
+    let casemapping = session.server.casemapping;
+    let parsed = Message::parse(line);
+    match parsed.command {
+        "CAP" => {
+            let args: Vec<&str> = parsed.all_params().collect();
+            let outcome = session.caps.handle(&args);
+            for line in &outcome.lines {
+                write_line(writer, line).await?;
+            }
+            if outcome.enabled_changed {
+                let enabled = session.caps.enabled();
+                session.shared.lock().capabilities = enabled.clone();
+                let _ = events.emit(
+                    "irc://event",
+                    IrcEvent::Capabilities {
+                        connection_id: connection_id.to_string(),
+                        enabled,
+                    },
+                );
+            }
+            if outcome.finished {
+                begin_registration(session, writer).await?;
+            }
+        }
+        "AUTHENTICATE" => {
+            let chunk = parsed.param(0).unwrap_or_default().to_string();
+            if let Some(mut sasl) = session.sasl.take() {
+                // SCRAM runs PBKDF2 with an iteration count the server picks;
+                // keep it off the runtime thread.
+                let (sasl, result) = tokio::task::spawn_blocking(move || {
+                    let result = sasl.respond(&chunk);
+                    (sasl, result)
+                })
+                .await?;
+                session.sasl = Some(sasl);
+                match result {
+                    Ok(lines) => {
+                        for line in &lines {
+                            write_line(writer, line).await?;
+                        }
+                    }
+                    Err(err) => {
+                        write_line(writer, "AUTHENTICATE *").await?;
+                        sasl_failed(session, writer, &err.to_string()).await?;
+                    }
+                }
+            }
+        }
+        "900" => {
+            let account = parsed.param(2).unwrap_or_default();
+            let msg = ChatMessage {
+                connection_id: connection_id.to_string(),
+                target: "*server".into(),
+                sender: None,
+                message: format!("Logged in as {account}"),
+                kind: MessageKind::Info,
+                timestamp: message_timestamp(&session.caps, &parsed),
+                metadata: message_metadata(&session.caps, &parsed),
+            };
+            scrollback.append(storage_key, casemapping, &msg).await.ok();
+            let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+        }
+        "903" | "907" => {
+            session.sasl = None;
+            session.sasl_done = true;
+            end_cap_negotiation(session, writer).await?;
+        }
+        "902" | "904" | "905" | "906" if !session.sasl_done => {
+            let reason = parsed.param(1).unwrap_or(parsed.command);
+            sasl_failed(session, writer, reason).await?;
+        }
+        "908" => {
+            tracing::debug!(
+                "server SASL mechanisms: {}",
+                parsed.param(1).unwrap_or_default()
+            );
+        }
+        "PING" => {
+            if let Some(arg) = parsed.param(0) {
+                let _ = write_priority(writer, &format!("PONG :{arg}")).await;
+            }
+        }
+        "PONG" => {
+            // `<server> :<token>`, though some servers send only the token.
+            let token = parsed.param(1).or(parsed.param(0)).unwrap_or_default();
+            if let Some(lag) = session.ping.pong(token) {
+                let _ = events.emit(
+                    "irc://event",
+                    IrcEvent::Lag {
+                        connection_id: connection_id.to_string(),
+                        lag_ms: lag.as_millis() as u64,
+                    },
+                );
+            }
+        }
+        "001" => {
+            // Welcome; servers without CAP support register us straight away.
+            session.caps.end();
+            if let Some(nick) = parsed.param(0).filter(|nick| !nick.is_empty()) {
+                session.nickname = nick.to_string();
+                session.shared.lock().nickname = nick.to_string();
+            }
+            if session.config.auth.uses_sasl() && !session.sasl_done {
+                sasl_failed(session, writer, "server registered us without SASL").await?;
+                if session.close_reason.is_some() {
+                    return Ok(());
+                }
+            }
+            let connection_id = session.id.as_str();
+            let config = &session.config;
+            let events = &session.events;
+            let _ = events.emit(
+                "irc://event",
+                IrcEvent::Connected {
                     connection_id: connection_id.to_string(),
-                    nickname: config.nickname.clone(),
+                    nickname: session.nickname.clone(),
                     server: config.server.clone(),
                     message: Some("welcome".into()),
                 },
             );
-            for channel in &config.auto_join {
-                let _ = write_line(writer, &format!("JOIN {channel}")).await;
+            session.registered = true;
+            session.shared.lock().registered = true;
+            session.registered_at = Some(Instant::now());
+            session.ping.registered(Instant::now());
+            writer.start_pacing();
+            if session.reconnect_attempt > 0 {
+                let _ = events.emit(
+                    "irc://event",
+                    IrcEvent::Reconnected {
+                        connection_id: connection_id.to_string(),
+                        attempt: session.reconnect_attempt,
+                    },
+                );
+            }
+            for channel in &session.rejoin {
+                let _ = write_built(writer, join_line(&session.server, channel)).await;
             }
         }
+        "005" => {
+            // `<nick> <token>... :are supported by this server`
+            let args: Vec<&str> = parsed.all_params().collect();
+            let [_, tokens @ .., _] = args.as_slice() else {
+                return Ok(());
+            };
+            session.server.apply(tokens);
+            session.shared.lock().server = session.server.clone();
+            let _ = session.events.emit(
+                "irc://event",
+                IrcEvent::ServerInfo {
+                    connection_id: session.id.clone(),
+                    info: session.server.clone(),
+                },
+            );
+        }
         "353" => {
-            if parsed.params.len() >= 3 {
-                let channel = parsed.params[2].clone();
-                let users = parsed
-                    .trailing
-                    .as_deref()
+            if let Some(channel) = parsed.param(2) {
+                let channel = channel.to_string();
+                let entries = parsed
+                    .param(3)
                     .unwrap_or_default()
                     .split_whitespace()
-                    .map(parse_channel_user)
+                    .map(|entry| split_names_entry(&session.server, entry))
                     .collect::<Vec<_>>();
-                let _ = app_handle.emit(
-                    "irc://event",
-                    IrcEvent::Names {
-                        connection_id: connection_id.to_string(),
-                        channel,
-                        users,
-                    },
-                );
+                let mut others = Vec::new();
+                {
+                    let mut channels = session.channels();
+                    for (nick, modes) in entries {
+                        if !channels.names_entry(casemapping, &channel, &nick, modes.clone()) {
+                            others.push(ChannelUserInfo {
+                                nick,
+                                modes: modes.into_iter().map(prefix_mode_name).collect(),
+                            });
+                        }
+                    }
+                }
+                if !others.is_empty() {
+                    let (_, users) = session
+                        .pending_names
+                        .entry(casemapping.fold(&channel))
+                        .or_insert_with(|| (channel.clone(), Vec::new()));
+                    users.extend(others);
+                }
+            }
+        }
+        "366" => {
+            let Some(channel) = parsed.param(1).map(str::to_string) else {
+                return Ok(());
+            };
+            let joined = session.channels().names_end(&session.server, &channel);
+            let (channel, users) = match joined {
+                Some(users) => (channel, users),
+                // A channel we are not in; an empty or hidden one ends
+                // without any 353.
+                None => session
+                    .pending_names
+                    .remove(&casemapping.fold(&channel))
+                    .unwrap_or_else(|| (channel, Vec::new())),
+            };
+            let _ = session.events.emit(
+                "irc://event",
+                IrcEvent::Names {
+                    connection_id: session.id.clone(),
+                    channel,
+                    users,
+                },
+            );
+        }
+        "329" => {
+            let (Some(channel), Some(created)) = (parsed.param(1), parsed.param(2)) else {
+                return Ok(());
+            };
+            if let Ok(created) = created.parse::<i64>() {
+                session
+                    .channels()
+                    .set_created(casemapping, channel, created.saturating_mul(1000));
             }
         }
         "332" => {
-            if parsed.params.len() >= 2 {
-                let channel = parsed.params[1].clone();
-                let topic = parsed.trailing.clone().unwrap_or_default();
-                let _ = app_handle.emit(
+            if let Some(channel) = parsed.param(1) {
+                let channel = channel.to_string();
+                let topic = parsed.param(2).unwrap_or_default().to_string();
+                session
+                    .channels()
+                    .set_topic(casemapping, &channel, Some(topic.clone()));
+                let _ = events.emit(
                     "irc://event",
                     IrcEvent::Topic {
                         connection_id: connection_id.to_string(),
                         channel: channel.clone(),
                         topic: topic.clone(),
                         setter: None,
+                        set_at: None,
                     },
                 );
                 let msg = ChatMessage {
@@ -551,151 +1596,454 @@ async fn handle_line(
                     sender: None,
                     message: format!("Topic: {topic}"),
                     kind: MessageKind::Topic,
-                    timestamp: current_timestamp(),
-                    metadata: None,
+                    timestamp: message_timestamp(&session.caps, &parsed),
+                    metadata: message_metadata(&session.caps, &parsed),
                 };
-                scrollback.append(storage_key, &msg).await.ok();
-                let _ = app_handle.emit("irc://event", IrcEvent::Message { data: msg });
+                scrollback.append(storage_key, casemapping, &msg).await.ok();
+                let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
             }
         }
+        "333" => {
+            let (Some(channel), Some(setter)) = (parsed.param(1), parsed.param(2)) else {
+                return Ok(());
+            };
+            let channel = channel.to_string();
+            // Some servers send the full nick!user@host mask.
+            let setter = Prefix::parse(setter).nick.to_string();
+            let set_at = parsed
+                .param(3)
+                .and_then(|time| time.parse::<i64>().ok())
+                .map(|secs| secs.saturating_mul(1000));
+            let topic = {
+                let mut channels = session.channels();
+                channels.set_topic_who(casemapping, &channel, &setter, set_at);
+                channels.topic(casemapping, &channel)
+            };
+            if let Some(topic) = topic {
+                let _ = events.emit(
+                    "irc://event",
+                    IrcEvent::Topic {
+                        connection_id: connection_id.to_string(),
+                        channel,
+                        topic,
+                        setter: Some(setter),
+                        set_at,
+                    },
+                );
+            }
+        }
+        "TOPIC" => {
+            let Some(channel) = parsed.param(0).map(str::to_string) else {
+                return Ok(());
+            };
+            let topic = parsed.param(1).unwrap_or_default().to_string();
+            let setter = parsed
+                .nick()
+                .map(str::to_string)
+                .unwrap_or_else(|| session.config.server.clone());
+            let timestamp = message_timestamp(&session.caps, &parsed);
+            {
+                let mut channels = session.channels();
+                channels.set_topic(casemapping, &channel, Some(topic.clone()));
+                channels.set_topic_who(casemapping, &channel, &setter, Some(timestamp));
+            }
+            let _ = events.emit(
+                "irc://event",
+                IrcEvent::Topic {
+                    connection_id: connection_id.to_string(),
+                    channel: channel.clone(),
+                    topic: topic.clone(),
+                    setter: Some(setter.clone()),
+                    set_at: Some(timestamp),
+                },
+            );
+            let text = if topic.is_empty() {
+                format!("{setter} cleared the topic")
+            } else {
+                format!("{setter} changed the topic to: {topic}")
+            };
+            let msg = ChatMessage {
+                connection_id: connection_id.to_string(),
+                target: channel,
+                sender: Some(setter),
+                message: text,
+                kind: MessageKind::Topic,
+                timestamp,
+                metadata: message_metadata(&session.caps, &parsed),
+            };
+            scrollback.append(storage_key, casemapping, &msg).await.ok();
+            let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+        }
         "PRIVMSG" => {
-            if let Some(target_raw) = parsed.params.get(0).cloned() {
-                let mut message = parsed
-                    .trailing
-                    .clone()
-                    .or_else(|| parsed.params.get(1).cloned())
-                    .unwrap_or_default();
-                let kind = if message.starts_with('\u{1}') && message.ends_with('\u{1}') {
-                    let body = message.trim_matches('\u{1}');
-                    if let Some(rest) = body.strip_prefix("ACTION ") {
-                        message = rest.to_string();
-                        MessageKind::Action
-                    } else {
-                        MessageKind::Privmsg
+            if let Some(target_raw) = parsed.param(0).map(str::to_string) {
+                let mut message = parsed.param(1).unwrap_or_default().to_string();
+                let mut kind = MessageKind::Privmsg;
+                if let Some(query) = ctcp::parse(&message) {
+                    if query.command != "ACTION" {
+                        let sender = parsed.nick().map(str::to_string);
+                        let reply = session.ctcp.respond(&query, Instant::now());
+                        if let (Some(sender), Some(reply)) = (&sender, reply) {
+                            write_line(writer, &format!("NOTICE {sender} :{reply}")).await?;
+                        }
+                        let mut text = format!(
+                            "CTCP {} from {}",
+                            query.command,
+                            sender.as_deref().unwrap_or("server")
+                        );
+                        if !query.params.is_empty() {
+                            text.push_str(&format!(": {}", query.params));
+                        }
+                        let msg = ChatMessage {
+                            connection_id: connection_id.to_string(),
+                            target: "*server".into(),
+                            sender,
+                            message: text,
+                            kind: MessageKind::Info,
+                            timestamp: message_timestamp(&session.caps, &parsed),
+                            metadata: message_metadata(&session.caps, &parsed),
+                        };
+                        scrollback.append(storage_key, casemapping, &msg).await.ok();
+                        let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+                        return Ok(());
                     }
-                } else {
-                    MessageKind::Privmsg
-                };
+                    let action = query.params.to_string();
+                    message = action;
+                    kind = MessageKind::Action;
+                }
                 let mut msg = ChatMessage {
                     connection_id: connection_id.to_string(),
                     target: target_raw.clone(),
-                    sender: parsed.prefix.and_then(extract_nick),
+                    sender: parsed.nick().map(str::to_string),
                     message,
                     kind,
-                    timestamp: current_timestamp(),
-                    metadata: None,
+                    timestamp: message_timestamp(&session.caps, &parsed),
+                    metadata: message_metadata(&session.caps, &parsed),
                 };
                 if let Some(sender) = &msg.sender {
-                    if equals_ignore_case(&msg.target, &config.nickname) {
+                    if !session.server.is_channel(&msg.target) {
                         msg.target = sender.clone();
+                        session.channels().add_query(casemapping, sender);
                     }
                 }
-                scrollback.append(storage_key, &msg).await.ok();
-                let _ = app_handle.emit("irc://event", IrcEvent::Message { data: msg });
+                scrollback.append(storage_key, casemapping, &msg).await.ok();
+                let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
             }
         }
         "NOTICE" => {
-            if let Some(target) = parsed.params.get(0).cloned() {
-                let message = parsed
-                    .trailing
-                    .clone()
-                    .or_else(|| parsed.params.get(1).cloned())
-                    .unwrap_or_default();
+            if let Some(mut target) = parsed.param(0).map(str::to_string) {
+                let mut message = parsed.param(1).unwrap_or_default().to_string();
+                let sender = parsed.nick().map(str::to_string);
+                if let Some(reply) = ctcp::parse(&message) {
+                    let from = sender.as_deref().unwrap_or("server");
+                    let text = match reply.command.as_str() {
+                        // We send our clock in milliseconds; see `ConnectionCommand::Ctcp`.
+                        "PING" => match reply.params.parse::<i64>() {
+                            Ok(sent) => format!(
+                                "CTCP PING reply from {from}: {:.3}s",
+                                current_timestamp().saturating_sub(sent) as f64 / 1000.0
+                            ),
+                            Err(_) => format!("CTCP PING reply from {from}: {}", reply.params),
+                        },
+                        command => format!("CTCP {command} reply from {from}: {}", reply.params),
+                    };
+                    target = "*server".into();
+                    message = text;
+                }
                 let msg = ChatMessage {
                     connection_id: connection_id.to_string(),
                     target,
-                    sender: parsed.prefix.and_then(extract_nick),
+                    sender,
                     message,
                     kind: MessageKind::Notice,
-                    timestamp: current_timestamp(),
-                    metadata: None,
+                    timestamp: message_timestamp(&session.caps, &parsed),
+                    metadata: message_metadata(&session.caps, &parsed),
                 };
-                scrollback.append(storage_key, &msg).await.ok();
-                let _ = app_handle.emit("irc://event", IrcEvent::Message { data: msg });
+                scrollback.append(storage_key, casemapping, &msg).await.ok();
+                let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
             }
         }
         "JOIN" => {
-            let channel = parsed
-                .params
-                .get(0)
-                .cloned()
-                .or_else(|| parsed.trailing.clone())
-                .unwrap_or_default();
-            let nick = parsed.prefix.as_ref().and_then(|p| extract_nick(p.clone()));
-            let nick = nick.unwrap_or_else(|| config.nickname.clone());
+            let channel = parsed.param(0).unwrap_or_default().to_string();
+            let nick = parsed.nick().map(str::to_string);
+            let nick = nick.unwrap_or_else(|| session.nickname.clone());
+            if casemapping.equals(&nick, &session.nickname) {
+                if let Some(userhost) = parsed.prefix.and_then(|prefix| prefix.userhost()) {
+                    session.userhost = Some(userhost.to_string());
+                }
+                session.channels().join(casemapping, &channel);
+                // Ask for RPL_CHANNELMODEIS; JOIN doesn't include the modes.
+                write_line(writer, &format!("MODE {channel}")).await?;
+            }
+            session.channels().add_member(casemapping, &channel, &nick);
             let msg = ChatMessage {
                 connection_id: connection_id.to_string(),
                 target: channel.clone(),
                 sender: Some(nick.clone()),
                 message: format!("{nick} joined {channel}"),
                 kind: MessageKind::Join,
-                timestamp: current_timestamp(),
-                metadata: None,
+                timestamp: message_timestamp(&session.caps, &parsed),
+                metadata: message_metadata(&session.caps, &parsed),
             };
-            scrollback.append(storage_key, &msg).await.ok();
-            let _ = app_handle.emit("irc://event", IrcEvent::Message { data: msg });
+            scrollback.append(storage_key, casemapping, &msg).await.ok();
+            let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
         }
         "PART" => {
-            if let Some(channel) = parsed.params.get(0) {
+            if let Some(channel) = parsed.param(0) {
                 let nick = parsed
-                    .prefix
-                    .as_ref()
-                    .and_then(|p| extract_nick(p.clone()))
-                    .unwrap_or_else(|| config.nickname.clone());
-                let reason = parsed
-                    .params
-                    .get(1)
-                    .cloned()
-                    .or_else(|| parsed.trailing.clone())
-                    .unwrap_or_default();
+                    .nick()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| session.nickname.clone());
+                let reason = parsed.param(1).unwrap_or_default();
+                if casemapping.equals(&nick, &session.nickname) {
+                    session.channels().part(casemapping, channel);
+                } else {
+                    session
+                        .channels()
+                        .remove_member(casemapping, channel, &nick);
+                }
                 let mut text = format!("{nick} left {channel}");
                 if !reason.is_empty() {
                     text.push_str(&format!(" ({reason})"));
                 }
                 let msg = ChatMessage {
                     connection_id: connection_id.to_string(),
-                    target: channel.clone(),
+                    target: channel.to_string(),
                     sender: Some(nick.clone()),
                     message: text,
                     kind: MessageKind::Part,
-                    timestamp: current_timestamp(),
-                    metadata: None,
+                    timestamp: message_timestamp(&session.caps, &parsed),
+                    metadata: message_metadata(&session.caps, &parsed),
                 };
-                scrollback.append(storage_key, &msg).await.ok();
-                let _ = app_handle.emit("irc://event", IrcEvent::Message { data: msg });
+                scrollback.append(storage_key, casemapping, &msg).await.ok();
+                let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+            }
+        }
+        "KICK" => {
+            let (Some(channel), Some(victim)) = (parsed.param(0), parsed.param(1)) else {
+                return Ok(());
+            };
+            let (channel, victim) = (channel.to_string(), victim.to_string());
+            let kicker = parsed.nick().map(str::to_string);
+            let reason = parsed.param(2).unwrap_or_default().to_string();
+            let own = casemapping.equals(&victim, &session.nickname);
+            if own {
+                session.channels().part(casemapping, &channel);
+                if session.config.rejoin_on_kick {
+                    let delay = Duration::from_secs(session.config.rejoin_delay_secs);
+                    // A delay too far out to represent means never.
+                    if let Some(at) = Instant::now().checked_add(delay) {
+                        session.kick_rejoins.push((at, channel.clone()));
+                    }
+                }
+            } else {
+                session
+                    .channels()
+                    .remove_member(casemapping, &channel, &victim);
+            }
+            let by = kicker.as_deref().unwrap_or("the server");
+            let mut text = if own {
+                format!("You were kicked from {channel} by {by}")
+            } else {
+                format!("{victim} was kicked by {by}")
+            };
+            if !reason.is_empty() {
+                text.push_str(&format!(" ({reason})"));
+            }
+            let mut metadata =
+                message_metadata(&session.caps, &parsed).unwrap_or_else(|| serde_json::json!({}));
+            metadata["victim"] = victim.into();
+            metadata["reason"] = reason.into();
+            let msg = ChatMessage {
+                connection_id: connection_id.to_string(),
+                target: channel,
+                sender: kicker,
+                message: text,
+                kind: MessageKind::Kick,
+                timestamp: message_timestamp(&session.caps, &parsed),
+                metadata: Some(metadata),
+            };
+            scrollback.append(storage_key, casemapping, &msg).await.ok();
+            let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+        }
+        "KILL"
+            if parsed
+                .param(0)
+                .is_some_and(|nick| casemapping.equals(nick, &session.nickname)) =>
+        {
+            let killer = parsed
+                .nick()
+                .map(str::to_string)
+                .unwrap_or_else(|| "the server".into());
+            let reason = parsed.param(1).unwrap_or_default();
+            session.close_reason = Some(SessionEnd::Lost(if reason.is_empty() {
+                format!("killed by {killer}")
+            } else {
+                format!("killed by {killer}: {reason}")
+            }));
+        }
+        "ERROR" => {
+            // The server is about to close the link; its text is the reason.
+            let reason = parsed.param(0).unwrap_or("server error").to_string();
+            session.close_reason = Some(SessionEnd::Lost(reason));
+        }
+        "MODE" => {
+            let args: Vec<&str> = parsed.all_params().collect();
+            let [target, modes, rest @ ..] = args.as_slice() else {
+                return Ok(());
+            };
+            // Servers set modes too; their prefix is the server name.
+            let setter = parsed
+                .nick()
+                .map(str::to_string)
+                .unwrap_or_else(|| session.config.server.clone());
+            let msg_target;
+            let text;
+            if session.server.is_channel(target) {
+                let changes = parse_mode_changes(&session.server, modes, rest);
+                if changes.is_empty() {
+                    return Ok(());
+                }
+                let (members, channel_modes) = {
+                    let mut channels = session.channels();
+                    let members = channels.apply_modes(&session.server, target, &changes);
+                    (members, channels.modes(casemapping, target))
+                };
+                for user in members {
+                    let _ = events.emit(
+                        "irc://event",
+                        IrcEvent::ChannelMember {
+                            connection_id: connection_id.to_string(),
+                            channel: target.to_string(),
+                            user,
+                        },
+                    );
+                }
+                if let Some(modes) = channel_modes {
+                    let _ = events.emit(
+                        "irc://event",
+                        IrcEvent::ChannelModes {
+                            connection_id: connection_id.to_string(),
+                            channel: target.to_string(),
+                            modes,
+                        },
+                    );
+                }
+                msg_target = target.to_string();
+                text = format!("{setter} sets mode {}", format_mode_changes(&changes));
+            } else {
+                // User modes never take parameters we care about.
+                msg_target = "*server".to_string();
+                text = format!("{setter} sets mode {modes} on {target}");
+            }
+            let msg = ChatMessage {
+                connection_id: connection_id.to_string(),
+                target: msg_target,
+                sender: Some(setter),
+                message: text,
+                kind: MessageKind::Mode,
+                timestamp: message_timestamp(&session.caps, &parsed),
+                metadata: message_metadata(&session.caps, &parsed),
+            };
+            scrollback.append(storage_key, casemapping, &msg).await.ok();
+            let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+        }
+        "324" => {
+            let args: Vec<&str> = parsed.all_params().collect();
+            let [_, channel, modes, rest @ ..] = args.as_slice() else {
+                return Ok(());
+            };
+            let changes = parse_mode_changes(&session.server, modes, rest);
+            let channel_modes = {
+                let mut channels = session.channels();
+                channels.set_modes(&session.server, channel, &changes);
+                channels.modes(casemapping, channel)
+            };
+            if let Some(modes) = channel_modes {
+                let _ = events.emit(
+                    "irc://event",
+                    IrcEvent::ChannelModes {
+                        connection_id: connection_id.to_string(),
+                        channel: channel.to_string(),
+                        modes,
+                    },
+                );
+            }
+        }
+        "NICK" => {
+            let Some(old) = parsed.nick().map(str::to_string) else {
+                return Ok(());
+            };
+            let new = parsed.param(0).unwrap_or_default().to_string();
+            if new.is_empty() {
+                return Ok(());
+            }
+            let own = casemapping.equals(&old, &session.nickname);
+            let targets = if own {
+                session.nickname = new.clone();
+                session.shared.lock().nickname = new.clone();
+                session.channels().rename_self(casemapping, &old, &new)
+            } else {
+                session.channels().rename(casemapping, &old, &new)
+            };
+            let _ = events.emit(
+                "irc://event",
+                IrcEvent::Nick {
+                    connection_id: connection_id.to_string(),
+                    old_nick: old.clone(),
+                    new_nick: new.clone(),
+                    own,
+                },
+            );
+            let text = if own {
+                format!("You are now known as {new}")
+            } else {
+                format!("{old} is now known as {new}")
+            };
+            for target in targets {
+                let msg = ChatMessage {
+                    connection_id: connection_id.to_string(),
+                    target,
+                    sender: Some(old.clone()),
+                    message: text.clone(),
+                    kind: MessageKind::Nick,
+                    timestamp: message_timestamp(&session.caps, &parsed),
+                    metadata: message_metadata(&session.caps, &parsed),
+                };
+                scrollback.append(storage_key, casemapping, &msg).await.ok();
+                let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
             }
         }
         "QUIT" => {
             let nick = parsed
-                .prefix
-                .as_ref()
-                .and_then(|p| extract_nick(p.clone()))
-                .unwrap_or_else(|| config.nickname.clone());
-            let reason = parsed
-                .params
-                .get(0)
-                .cloned()
-                .or_else(|| parsed.trailing.clone())
-                .unwrap_or_default();
+                .nick()
+                .map(str::to_string)
+                .unwrap_or_else(|| session.nickname.clone());
+            let reason = parsed.param(0).unwrap_or_default();
+            if casemapping.equals(&nick, &session.nickname) {
+                return Ok(());
+            }
             let text = if reason.is_empty() {
                 format!("{nick} quit")
             } else {
                 format!("{nick} quit: {reason}")
             };
-            let msg = ChatMessage {
-                connection_id: connection_id.to_string(),
-                target: nick.clone(),
-                sender: Some(nick),
-                message: text,
-                kind: MessageKind::Quit,
-                timestamp: current_timestamp(),
-                metadata: None,
-            };
-            scrollback.append(storage_key, &msg).await.ok();
-            let _ = app_handle.emit("irc://event", IrcEvent::Message { data: msg });
+            let channels = session.channels().remove_everywhere(casemapping, &nick);
+            for channel in channels {
+                let msg = ChatMessage {
+                    connection_id: connection_id.to_string(),
+                    target: channel,
+                    sender: Some(nick.clone()),
+                    message: text.clone(),
+                    kind: MessageKind::Quit,
+                    timestamp: message_timestamp(&session.caps, &parsed),
+                    metadata: message_metadata(&session.caps, &parsed),
+                };
+                scrollback.append(storage_key, casemapping, &msg).await.ok();
+                let _ = events.emit("irc://event", IrcEvent::Message { data: msg });
+            }
         }
         "433" => {
-            let _ = app_handle.emit(
+            let _ = events.emit(
                 "irc://event",
                 IrcEvent::Error {
                     connection_id: connection_id.to_string(),
@@ -708,79 +2056,59 @@ async fn handle_line(
     Ok(())
 }
 
-fn extract_nick(prefix: String) -> Option<String> {
-    prefix
-        .split('!')
-        .next()
-        .filter(|s| !s.is_empty())
-        .map(|s| s.to_string())
-}
-
-fn equals_ignore_case(a: &str, b: &str) -> bool {
-    a.eq_ignore_ascii_case(b)
-}
-
-fn parse_channel_user(entry: &str) -> ChannelUserInfo {
+/// Splits a `NAMES` entry such as `@+nick` into the nick and its membership
+/// modes, using the server's `PREFIX` (several prefixes with `multi-prefix`).
+fn split_names_entry(server: &ServerInfo, entry: &str) -> (String, Vec<char>) {
     let mut modes = Vec::new();
     let mut nick_start = 0;
     for (idx, ch) in entry.char_indices() {
-        let mode = match ch {
-            '~' => Some("owner"),
-            '&' => Some("admin"),
-            '@' => Some("op"),
-            '%' => Some("halfop"),
-            '+' => Some("voice"),
-            _ => None,
-        };
-        if let Some(mode) = mode {
-            modes.push(mode.to_string());
+        if let Some(mode) = server.mode_for_prefix(ch) {
+            modes.push(mode);
             nick_start = idx + ch.len_utf8();
         } else {
             break;
         }
     }
-    let nick = entry[nick_start..].to_string();
-    ChannelUserInfo { nick, modes }
+    // userhost-in-names entries carry the full mask.
+    let nick = entry[nick_start..]
+        .split('!')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    (nick, modes)
 }
 
-#[derive(Debug)]
-struct ParsedMessage {
-    prefix: Option<String>,
-    command: String,
-    params: Vec<String>,
-    trailing: Option<String>,
-}
-
-fn parse_message(line: &str) -> ParsedMessage {
-    let mut rest = line.trim().to_string();
-    let mut prefix = None;
-    if rest.starts_with(':') {
-        if let Some(idx) = rest.find(' ') {
-            prefix = Some(rest[1..idx].to_string());
-            rest = rest[idx + 1..].to_string();
-        } else {
-            return ParsedMessage {
-                prefix,
-                command: rest,
-                params: Vec::new(),
-                trailing: None,
-            };
-        }
+/// Builds the `ChatMessage.metadata` payload for an incoming line, exposing
+/// its message tags (msgid, account, time, client-only tags, ...) to the UI.
+/// Messages stamped with server time also keep the local receive time.
+fn message_metadata(caps: &CapNegotiator, parsed: &Message<'_>) -> Option<serde_json::Value> {
+    let mut metadata = serde_json::Map::new();
+    if !parsed.tags.is_empty() {
+        metadata.insert("tags".into(), serde_json::json!(parsed.tags.to_map()));
     }
-    let mut trailing = None;
-    let mut parts = rest.splitn(2, " :");
-    let head = parts.next().unwrap_or("");
-    if let Some(t) = parts.next() {
-        trailing = Some(t.to_string());
+    if server_time(caps, parsed).is_some() {
+        metadata.insert("receivedAt".into(), current_timestamp().into());
     }
-    let mut iter = head.split_whitespace();
-    let command = iter.next().unwrap_or("").to_string();
-    let params = iter.map(|s| s.to_string()).collect();
-    ParsedMessage {
-        prefix,
-        command,
-        params,
-        trailing,
+    (!metadata.is_empty()).then_some(serde_json::Value::Object(metadata))
+}
+
+/// The time an incoming line was sent: the `time` tag when `server-time` is
+/// enabled (accurate for bouncer playback and lagged links), otherwise now.
+fn message_timestamp(caps: &CapNegotiator, parsed: &Message<'_>) -> i64 {
+    server_time(caps, parsed).unwrap_or_else(current_timestamp)
+}
+
+fn server_time(caps: &CapNegotiator, parsed: &Message<'_>) -> Option<i64> {
+    if !caps.is_enabled("server-time") {
+        return None;
+    }
+    let time = parsed.tags.get("time")?;
+    match DateTime::parse_from_rfc3339(&time) {
+        Ok(time) => Some(time.timestamp_millis()),
+        Err(err) => {
+            tracing::debug!("ignoring invalid server-time {time:?}: {err}");
+            None
+        }
     }
 }
 
@@ -790,3 +2118,550 @@ fn current_timestamp() -> i64 {
         .map(|d| d.as_millis() as i64)
         .unwrap_or(0)
 }
+
+/// Feeds `input` through the line handler of a fresh session, one line per
+/// `\n`, as though a server had sent it. Events are discarded and replies
+/// written nowhere. Used by the fuzz targets.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_handle_lines(input: &[u8]) {
+    use std::sync::OnceLock;
+    use tokio::runtime::Runtime;
+
+    /// Built once per process so each input doesn't pay for a runtime.
+    static HARNESS: OnceLock<(Runtime, ConnectionConfig)> = OnceLock::new();
+    let (runtime, config) = HARNESS.get_or_init(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build runtime");
+        let config = serde_json::from_value(serde_json::json!({
+            "server": "irc.example.org",
+            "port": 6697,
+            "useTls": true,
+            "nickname": "fluxchat",
+            "autoJoin": ["#fluxchat"],
+            "rejoinOnKick": true,
+        }))
+        .expect("invalid fuzzing config");
+        (runtime, config)
+    });
+    let mut session = Session::new(
+        "fuzz".into(),
+        config.storage_key(),
+        config.clone(),
+        EventSink::Discard,
+        ScrollbackStore::discard(),
+        Arc::new(Mutex::new(SharedState::default())),
+    );
+    session.connected = true;
+    let writer: AnyWriter = Box::new(tokio::io::sink());
+    let mut writer = SendQueue::new(writer, config.encoding, config.flood_burst, Duration::ZERO);
+    runtime.block_on(async {
+        for bytes in input.split(|&byte| byte == b'\n') {
+            let line = decode_incoming(&session, bytes);
+            let _ = handle_line(&mut session, &mut writer, &line).await;
+            if session.close_reason.is_some() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use super::*;
+
+    /// A session wired to an in-memory "server": lines are fed to
+    /// `handle_line` and whatever the client writes can be read back.
+    struct MockServer {
+        session: Session,
+        writer: Outbox,
+        server: DuplexStream,
+    }
+
+    impl MockServer {
+        /// A session for `fluxchat` on irc.example.org, with `overrides`
+        /// merged into its config.
+        fn new(overrides: serde_json::Value) -> Self {
+            let mut config = serde_json::json!({
+                "server": "irc.example.org",
+                "port": 6697,
+                "useTls": true,
+                "nickname": "fluxchat",
+                "autoJoin": [],
+                "floodBurst": 1000,
+            });
+            if let (Some(config), serde_json::Value::Object(overrides)) =
+                (config.as_object_mut(), overrides)
+            {
+                config.extend(overrides);
+            }
+            let config: ConnectionConfig = serde_json::from_value(config).unwrap();
+            let (client, server) = tokio::io::duplex(1 << 16);
+            let writer: AnyWriter = Box::new(client);
+            let writer =
+                SendQueue::new(writer, config.encoding, config.flood_burst, Duration::ZERO);
+            let session = Session::new(
+                "test".into(),
+                config.storage_key(),
+                config,
+                EventSink::Discard,
+                ScrollbackStore::discard(),
+                Arc::new(Mutex::new(SharedState::default())),
+            );
+            Self {
+                session,
+                writer,
+                server,
+            }
+        }
+
+        async fn feed(&mut self, lines: &[&str]) {
+            for line in lines {
+                handle_line(&mut self.session, &mut self.writer, line)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        /// Lines written by the client since the last call.
+        fn sent(&mut self) -> Vec<String> {
+            let mut buf = Vec::new();
+            while let Some(Ok(n)) = self.server.read_buf(&mut buf).now_or_never() {
+                if n == 0 {
+                    break;
+                }
+            }
+            String::from_utf8(buf)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+
+        fn members(&self, channel: &str) -> Vec<String> {
+            let snapshot = self
+                .session
+                .channels()
+                .snapshot(&self.session.server, channel);
+            snapshot
+                .map(|snapshot| snapshot.members.into_iter().map(|user| user.nick).collect())
+                .unwrap_or_default()
+        }
+
+        /// Registers and joins `#c` with `alice` and `bob` in it.
+        async fn in_channel(&mut self) {
+            self.feed(&[
+                ":s 001 fluxchat :Welcome",
+                ":fluxchat!u@h JOIN #c",
+                ":s 353 fluxchat = #c :fluxchat alice bob",
+                ":s 366 fluxchat #c :End of /NAMES list.",
+            ])
+            .await;
+            self.sent();
+        }
+    }
+
+    // Each handler must read its last param the same whether or not the
+    // server put a `:` in front of it.
+
+    #[tokio::test]
+    async fn welcome_takes_nick_from_either_form() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.feed(&[":s 001 :fluxchat_"]).await;
+        assert_eq!(server.session.nickname, "fluxchat_");
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.feed(&[":s 001 fluxchat_ :Welcome"]).await;
+        assert_eq!(server.session.nickname, "fluxchat_");
+    }
+
+    #[tokio::test]
+    async fn isupport_ignores_trailing_text() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server
+            .feed(&[":s 005 fluxchat CHANTYPES=& :are supported by this server"])
+            .await;
+        assert!(server.session.server.is_channel("&c"));
+        assert!(!server.session.server.is_channel("#c"));
+        server.feed(&[":s 005 fluxchat :CHANTYPES=#"]).await;
+        assert!(!server.session.server.is_channel("#c"));
+    }
+
+    #[tokio::test]
+    async fn names_reply_in_either_form() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server
+            .feed(&[
+                ":s 001 fluxchat :Welcome",
+                ":fluxchat!u@h JOIN :#c",
+                ":s 353 fluxchat = #c fluxchat",
+                ":s 353 fluxchat = #c :alice bob",
+                ":s 366 fluxchat :#c",
+            ])
+            .await;
+        assert_eq!(server.members("#c"), ["alice", "bob", "fluxchat"]);
+    }
+
+    #[tokio::test]
+    async fn topic_numerics_in_either_form() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.in_channel().await;
+        server
+            .feed(&[
+                ":s 332 fluxchat #c Welcome",
+                ":s 333 fluxchat #c :alice",
+                ":s 329 fluxchat #c :1600000000",
+            ])
+            .await;
+        let snapshot = server
+            .session
+            .channels()
+            .snapshot(&server.session.server, "#c")
+            .unwrap();
+        assert_eq!(snapshot.topic.as_deref(), Some("Welcome"));
+        assert_eq!(snapshot.topic_setter.as_deref(), Some("alice"));
+        assert_eq!(snapshot.created, Some(1_600_000_000_000));
+        server.feed(&[":alice!u@h TOPIC #c :new topic"]).await;
+        assert_eq!(
+            server.session.channels().topic(CaseMapping::Rfc1459, "#c"),
+            Some("new topic".into())
+        );
+        server.feed(&[":alice!u@h TOPIC #c other"]).await;
+        assert_eq!(
+            server.session.channels().topic(CaseMapping::Rfc1459, "#c"),
+            Some("other".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn kick_with_colon_victim() {
+        let mut server = MockServer::new(serde_json::json!({ "rejoinOnKick": true }));
+        server.in_channel().await;
+        server.feed(&[":alice!u@h KICK #c :bob"]).await;
+        assert_eq!(server.members("#c"), ["alice", "fluxchat"]);
+        server.feed(&[":alice!u@h KICK #c fluxchat :bye"]).await;
+        assert!(server.members("#c").is_empty());
+        assert_eq!(server.session.kick_rejoins.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn part_nick_and_quit_in_either_form() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.in_channel().await;
+        server.feed(&[":alice!u@h NICK :carol"]).await;
+        assert_eq!(server.members("#c"), ["bob", "carol", "fluxchat"]);
+        server.feed(&[":bob!u@h PART :#c"]).await;
+        assert_eq!(server.members("#c"), ["carol", "fluxchat"]);
+        server.feed(&[":carol!u@h QUIT"]).await;
+        assert_eq!(server.members("#c"), ["fluxchat"]);
+    }
+
+    #[tokio::test]
+    async fn long_action_is_rewrapped_per_line() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.in_channel().await;
+        let text = vec!["waves"; 200].join(" ");
+        send_message(
+            &mut server.session,
+            &mut server.writer,
+            "#c".into(),
+            text.clone(),
+            MessageKind::Action,
+        )
+        .await;
+        let sent = server.sent();
+        assert!(sent.len() > 1);
+        let userhost = server.session.userhost.clone().unwrap();
+        let mut chunks = Vec::new();
+        for line in &sent {
+            let body = line
+                .strip_prefix("PRIVMSG #c :\u{1}ACTION ")
+                .and_then(|body| body.strip_suffix('\u{1}'))
+                .unwrap_or_else(|| panic!("not an action: {line:?}"));
+            // As the server relays it to the channel.
+            let relayed = format!(":fluxchat!{userhost} {line}\r\n");
+            assert!(relayed.len() <= 512, "{} bytes", relayed.len());
+            chunks.push(body);
+        }
+        assert_eq!(chunks.join(" "), text);
+    }
+
+    #[test]
+    fn incoming_lines_fall_back_per_channel() {
+        let server = MockServer::new(serde_json::json!({
+            "fallbackEncoding": "windows-1252",
+            "channelEncodings": { "#Latin": "iso-8859-1" },
+        }));
+        let decode = |bytes: &[u8]| decode_incoming(&server.session, bytes);
+        assert_eq!(
+            decode(b":a PRIVMSG #other :\x93hi\x94\r\n"),
+            ":a PRIVMSG #other :\u{201C}hi\u{201D}"
+        );
+        assert_eq!(
+            decode(b":a PRIVMSG #latin :\x93hi\x94\r\n"),
+            ":a PRIVMSG #latin :\u{93}hi\u{94}"
+        );
+        assert_eq!(
+            decode(":a PRIVMSG #latin :\u{201C}hi\u{201D}\r\n".as_bytes()),
+            ":a PRIVMSG #latin :\u{201C}hi\u{201D}"
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_in_either_form() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.feed(&["PING token", "PING :two words"]).await;
+        assert_eq!(server.sent(), ["PONG :token", "PONG :two words"]);
+    }
+
+    #[tokio::test]
+    async fn pong_matches_with_or_without_server_param() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.in_channel().await;
+        let mut now = Instant::now();
+        for form in ["PONG s :{token}", "PONG :{token}"] {
+            now += server.session.ping.interval;
+            let token = server.session.ping.due(now).unwrap();
+            server.feed(&[&form.replace("{token}", &token)]).await;
+            assert!(server.session.ping.outstanding.is_none(), "{form}");
+        }
+    }
+
+    #[tokio::test]
+    async fn registration_times_out_until_welcomed() {
+        let mut server = MockServer::new(serde_json::json!({ "pingTimeoutSecs": 30 }));
+        let connected = Instant::now();
+        server.session.ping.connected(connected);
+        let deadline = connected + Duration::from_secs(30);
+        assert!(!server.session.ping.registration_timed_out(connected));
+        assert!(server.session.ping.registration_timed_out(deadline));
+        server.feed(&[":s 001 fluxchat :Welcome"]).await;
+        assert!(!server.session.ping.registration_timed_out(deadline));
+    }
+
+    #[tokio::test]
+    async fn kill_and_error_close_the_session() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.feed(&[":op!u@h KILL :fluxchat"]).await;
+        assert!(matches!(
+            server.session.close_reason,
+            Some(SessionEnd::Lost(ref reason)) if reason == "killed by op"
+        ));
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.feed(&["ERROR Closing"]).await;
+        assert!(matches!(
+            server.session.close_reason,
+            Some(SessionEnd::Lost(ref reason)) if reason == "Closing"
+        ));
+    }
+
+    #[tokio::test]
+    async fn backoff_only_resets_after_a_stable_session() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.feed(&["ERROR :Closing link"]).await;
+        assert!(!server.session.was_stable(Instant::now() + STABLE_SESSION));
+
+        let mut server = MockServer::new(serde_json::json!({}));
+        server
+            .feed(&[":s 001 fluxchat :Welcome", "ERROR :Closing link"])
+            .await;
+        assert!(server.session.close_reason.is_some());
+        let registered_at = server.session.registered_at.unwrap();
+        assert!(!server.session.was_stable(Instant::now()));
+        assert!(!server
+            .session
+            .was_stable(registered_at + STABLE_SESSION - Duration::from_secs(1)));
+        assert!(server.session.was_stable(registered_at + STABLE_SESSION));
+    }
+
+    #[tokio::test]
+    async fn shared_state_tracks_nick_registration_and_channels() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.in_channel().await;
+        server.feed(&[":fluxchat!u@h NICK fluxchat_"]).await;
+        let shared = server.session.shared.lock();
+        assert_eq!(shared.nickname, "fluxchat_");
+        assert!(shared.registered);
+        let channels = shared.channels.snapshots(&shared.server);
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].name, "#c");
+        assert_eq!(channels[0].members.len(), 3);
+    }
+
+    #[test]
+    fn config_validation_rejects_malformed_fields() {
+        let config =
+            |overrides: serde_json::Value| MockServer::new(overrides).session.config.validate();
+        assert!(config(serde_json::json!({ "autoJoin": ["#a", "&b"] })).is_ok());
+        assert!(matches!(
+            config(serde_json::json!({ "nickname": "flux chat" })),
+            Err(OutgoingError::InvalidNick(_))
+        ));
+        assert!(matches!(
+            config(serde_json::json!({ "username": "a b" })),
+            Err(OutgoingError::InvalidParam("username"))
+        ));
+        assert!(matches!(
+            config(serde_json::json!({ "realname": "Flux\r\nQUIT" })),
+            Err(OutgoingError::ForbiddenChar("realname"))
+        ));
+        assert!(matches!(
+            config(serde_json::json!({ "autoJoin": ["#ok", "#bad\nQUIT"] })),
+            Err(OutgoingError::ForbiddenChar("channel"))
+        ));
+        assert!(matches!(
+            config(serde_json::json!({ "autoJoin": ["nochan"] })),
+            Err(OutgoingError::InvalidChannel(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn auto_join_skips_malformed_channels() {
+        let mut server = MockServer::new(serde_json::json!({}));
+        server.session.rejoin = vec!["#ok".into(), "#a b".into(), "nochan".into()];
+        server.feed(&[":s 001 fluxchat :Welcome"]).await;
+        assert_eq!(server.sent(), ["JOIN #ok"]);
+    }
+
+    #[tokio::test]
+    async fn kick_with_an_unrepresentable_rejoin_delay_is_ignored() {
+        let mut server = MockServer::new(serde_json::json!({
+            "rejoinOnKick": true,
+            "rejoinDelaySecs": u64::MAX,
+        }));
+        server.in_channel().await;
+        server.feed(&[":op!u@h KICK #c fluxchat :bye"]).await;
+        assert!(server.session.kick_rejoins.is_empty());
+    }
+
+    #[test]
+    fn pkcs12_password_is_not_serialized() {
+        let cert: ClientCertificate = serde_json::from_value(serde_json::json!({
+            "format": "pkcs12",
+            "path": "/certs/me.p12",
+            "password": "hunter2",
+        }))
+        .unwrap();
+        assert!(matches!(
+            cert,
+            ClientCertificate::Pkcs12 { password: Some(ref password), .. } if password == "hunter2"
+        ));
+        assert_eq!(
+            serde_json::to_value(&cert).unwrap(),
+            serde_json::json!({ "format": "pkcs12", "path": "/certs/me.p12" })
+        );
+    }
+
+    // SASL as a server drives it: CAP LS mechanism list, AUTHENTICATE, then
+    // 903 or 904.
+
+    fn sasl_config(mode: &str, required: bool) -> serde_json::Value {
+        serde_json::json!({
+            "auth": { "mode": mode, "account": "acct", "password": "pw" },
+            "saslRequired": required,
+            "requestedCaps": ["multi-prefix"],
+        })
+    }
+
+    /// Runs the handshake and CAP negotiation up to the first AUTHENTICATE.
+    async fn start_sasl(server: &mut MockServer, offered: &str) -> Vec<String> {
+        perform_handshake(&server.session.config.clone(), &mut server.writer)
+            .await
+            .unwrap();
+        assert_eq!(
+            server.sent(),
+            ["CAP LS 302", "NICK fluxchat", "USER fluxchat 0 * :fluxchat"]
+        );
+        server
+            .feed(&[&format!(":s CAP * LS :multi-prefix sasl={offered}")])
+            .await;
+        assert_eq!(server.sent(), ["CAP REQ :multi-prefix sasl"]);
+        server
+            .feed(&[":s CAP fluxchat ACK :multi-prefix sasl"])
+            .await;
+        server.sent()
+    }
+
+    #[tokio::test]
+    async fn sasl_plain_login() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let mut server = MockServer::new(sasl_config("saslPlain", true));
+        assert_eq!(
+            start_sasl(&mut server, "PLAIN,EXTERNAL").await,
+            ["AUTHENTICATE PLAIN"]
+        );
+        server.feed(&["AUTHENTICATE +"]).await;
+        let payload = STANDARD.encode("acct\0acct\0pw");
+        assert_eq!(server.sent(), [format!("AUTHENTICATE {payload}")]);
+        server
+            .feed(&[
+                ":s 900 fluxchat fluxchat!u@h acct :You are now logged in as acct",
+                ":s 903 fluxchat :SASL authentication successful",
+            ])
+            .await;
+        assert_eq!(server.sent(), ["CAP END"]);
+        assert!(server.session.sasl_done);
+        assert!(server.session.close_reason.is_none());
+    }
+
+    #[tokio::test]
+    async fn sasl_scram_picks_an_offered_hash() {
+        let mut server = MockServer::new(sasl_config("saslScram", true));
+        assert_eq!(
+            start_sasl(&mut server, "PLAIN,SCRAM-SHA-1").await,
+            ["AUTHENTICATE SCRAM-SHA-1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn sasl_failure_continues_when_optional() {
+        let mut server = MockServer::new(sasl_config("saslPlain", false));
+        start_sasl(&mut server, "PLAIN").await;
+        server
+            .feed(&[
+                "AUTHENTICATE +",
+                ":s 904 fluxchat :SASL authentication failed",
+            ])
+            .await;
+        assert_eq!(server.sent().last().map(String::as_str), Some("CAP END"));
+        assert!(server.session.close_reason.is_none());
+    }
+
+    #[tokio::test]
+    async fn sasl_failure_aborts_when_required() {
+        let mut server = MockServer::new(sasl_config("saslPlain", true));
+        start_sasl(&mut server, "PLAIN").await;
+        server
+            .feed(&[
+                "AUTHENTICATE +",
+                ":s 904 fluxchat :SASL authentication failed",
+            ])
+            .await;
+        assert_eq!(
+            server.sent().last().map(String::as_str),
+            Some("QUIT :SASL authentication failed")
+        );
+        // Reconnecting would only resend the same bad credentials.
+        assert!(matches!(
+            server.session.close_reason,
+            Some(SessionEnd::Abort(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sasl_required_but_mechanism_not_offered() {
+        let mut server = MockServer::new(sasl_config("saslPlain", true));
+        assert_eq!(
+            start_sasl(&mut server, "EXTERNAL").await,
+            ["QUIT :SASL authentication failed"]
+        );
+        assert!(matches!(
+            server.session.close_reason,
+            Some(SessionEnd::Abort(_))
+        ));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// At most this many automatic replies are sent per `REPLY_WINDOW`, so a
+/// flood of queries can't get us disconnected for excess flood.
+const MAX_REPLIES: usize = 3;
+const REPLY_WINDOW: Duration = Duration::from_secs(10);
+
+const DELIM: char = '\u{1}';
+
+/// A CTCP message carried in a PRIVMSG (query) or NOTICE (reply) body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ctcp<'a> {
+    /// Uppercased command, e.g. `VERSION`.
+    pub command: String,
+    pub params: &'a str,
+}
+
+/// Parses `\x01COMMAND params\x01`. Some clients omit the closing delimiter.
+pub fn parse(text: &str) -> Option<Ctcp<'_>> {
+    let body = text.strip_prefix(DELIM)?;
+    let body = body.strip_suffix(DELIM).unwrap_or(body);
+    let (command, params) = body.split_once(' ').unwrap_or((body, ""));
+    if command.is_empty() {
+        return None;
+    }
+    Some(Ctcp {
+        command: command.to_ascii_uppercase(),
+        params,
+    })
+}
+
+pub fn encode(command: &str, params: &str) -> String {
+    if params.is_empty() {
+        format!("{DELIM}{command}{DELIM}")
+    } else {
+        format!("{DELIM}{command} {params}{DELIM}")
+    }
+}
+
+/// How we answer CTCP queries. Unset text replies are not answered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CtcpConfig {
+    /// Answer queries at all.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_version")]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub userinfo: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub ping: bool,
+    #[serde(default = "default_enabled")]
+    pub time: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_version() -> Option<String> {
+    Some(format!("FluxChat {}", env!("CARGO_PKG_VERSION")))
+}
+
+impl Default for CtcpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            version: default_version(),
+            source: None,
+            userinfo: None,
+            ping: true,
+            time: true,
+        }
+    }
+}
+
+/// Answers CTCP queries according to a `CtcpConfig`, rate limited.
+#[derive(Debug)]
+pub struct CtcpResponder {
+    config: CtcpConfig,
+    sent: VecDeque<Instant>,
+}
+
+impl CtcpResponder {
+    pub fn new(config: CtcpConfig) -> Self {
+        Self {
+            config,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// The NOTICE body to send back for `query`, if we answer it and are not
+    /// over the rate limit.
+    pub fn respond(&mut self, query: &Ctcp<'_>, now: Instant) -> Option<String> {
+        let params = self.answer(query)?;
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= REPLY_WINDOW)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= MAX_REPLIES {
+            tracing::debug!("not answering CTCP {}: rate limited", query.command);
+            return None;
+        }
+        self.sent.push_back(now);
+        Some(encode(&query.command, &params))
+    }
+
+    fn answer(&self, query: &Ctcp<'_>) -> Option<String> {
+        let config = &self.config;
+        if !config.enabled {
+            return None;
+        }
+        match query.command.as_str() {
+            "VERSION" => config.version.clone(),
+            "SOURCE" => config.source.clone(),
+            "USERINFO" => config.userinfo.clone(),
+            "PING" if config.ping => Some(query.params.to_string()),
+            "TIME" if config.time => Some(
+                chrono::Local::now()
+                    .format("%a, %d %b %Y %H:%M:%S %z")
+                    .to_string(),
+            ),
+            "CLIENTINFO" => Some(self.supported().join(" ")),
+            _ => None,
+        }
+    }
+
+    /// The queries we currently answer, for `CLIENTINFO`.
+    fn supported(&self) -> Vec<&'static str> {
+        let config = &self.config;
+        [
+            ("ACTION", true),
+            ("CLIENTINFO", true),
+            ("PING", config.ping),
+            ("SOURCE", config.source.is_some()),
+            ("TIME", config.time),
+            ("USERINFO", config.userinfo.is_some()),
+            ("VERSION", config.version.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(command, enabled)| enabled.then_some(command))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> Ctcp<'_> {
+        parse(text).unwrap()
+    }
+
+    #[test]
+    fn parses_command_and_params() {
+        assert_eq!(
+            parse("\u{1}ping 1700000000 123\u{1}"),
+            Some(Ctcp {
+                command: "PING".into(),
+                params: "1700000000 123",
+            })
+        );
+        assert_eq!(
+            parse("\u{1}ACTION waves  twice \u{1}"),
+            Some(Ctcp {
+                command: "ACTION".into(),
+                params: "waves  twice ",
+            })
+        );
+        assert_eq!(
+            parse("\u{1}VERSION\u{1}"),
+            Some(Ctcp {
+                command: "VERSION".into(),
+                params: "",
+            })
+        );
+    }
+
+    #[test]
+    fn tolerates_a_missing_closing_delimiter() {
+        assert_eq!(query("\u{1}ACTION waves").params, "waves");
+    }
+
+    #[test]
+    fn plain_text_is_not_ctcp() {
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse("hi \u{1}VERSION\u{1}"), None);
+        assert_eq!(parse("\u{1}\u{1}"), None);
+        assert_eq!(parse("\u{1} VERSION\u{1}"), None);
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        assert_eq!(encode("VERSION", ""), "\u{1}VERSION\u{1}");
+        let encoded = encode("ACTION", "waves at you");
+        assert_eq!(encoded, "\u{1}ACTION waves at you\u{1}");
+        assert_eq!(query(&encoded).params, "waves at you");
+    }
+
+    #[test]
+    fn answers_configured_queries() {
+        let mut responder = CtcpResponder::new(CtcpConfig {
+            version: Some("FluxChat test".into()),
+            userinfo: Some("hi there".into()),
+            ..CtcpConfig::default()
+        });
+        let now = Instant::now();
+        assert_eq!(
+            responder.respond(&query("\u{1}VERSION\u{1}"), now),
+            Some("\u{1}VERSION FluxChat test\u{1}".into())
+        );
+        assert_eq!(
+            responder.respond(&query("\u{1}PING 42\u{1}"), now),
+            Some("\u{1}PING 42\u{1}".into())
+        );
+        assert_eq!(
+            responder.respond(&query("\u{1}CLIENTINFO\u{1}"), now),
+            Some("\u{1}CLIENTINFO ACTION CLIENTINFO PING TIME USERINFO VERSION\u{1}".into())
+        );
+    }
+
+    #[test]
+    fn unset_or_disabled_queries_go_unanswered() {
+        let now = Instant::now();
+        let mut responder = CtcpResponder::new(CtcpConfig {
+            version: None,
+            ping: false,
+            ..CtcpConfig::default()
+        });
+        assert_eq!(responder.respond(&query("\u{1}VERSION\u{1}"), now), None);
+        assert_eq!(responder.respond(&query("\u{1}SOURCE\u{1}"), now), None);
+        assert_eq!(responder.respond(&query("\u{1}PING 1\u{1}"), now), None);
+        assert_eq!(responder.respond(&query("\u{1}FINGER\u{1}"), now), None);
+        assert!(responder.respond(&query("\u{1}TIME\u{1}"), now).is_some());
+
+        let mut responder = CtcpResponder::new(CtcpConfig {
+            enabled: false,
+            ..CtcpConfig::default()
+        });
+        assert_eq!(responder.respond(&query("\u{1}TIME\u{1}"), now), None);
+    }
+
+    #[test]
+    fn replies_are_rate_limited() {
+        let mut responder = CtcpResponder::new(CtcpConfig::default());
+        let ping = query("\u{1}PING 1\u{1}");
+        let start = Instant::now();
+        for _ in 0..MAX_REPLIES {
+            assert!(responder.respond(&ping, start).is_some());
+        }
+        assert_eq!(responder.respond(&ping, start + REPLY_WINDOW / 2), None);
+        assert!(responder.respond(&ping, start + REPLY_WINDOW).is_some());
+    }
+}
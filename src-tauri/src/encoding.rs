@@ -0,0 +1,156 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+/// Character sets we can read and write. Anything that isn't valid UTF-8 is
+/// decoded with a configured fallback instead of dropping the connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "iso-8859-1")]
+    Latin1,
+    /// Latin-1 with printable characters in place of most C1 controls; what
+    /// "Latin-1" text from Windows clients usually really is.
+    #[serde(rename = "windows-1252")]
+    Windows1252,
+}
+
+/// Windows-1252 bytes 0x80-0x9F. The five unassigned bytes map to the C1
+/// control of the same value, like Latin-1.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+impl Encoding {
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Latin1 => bytes.iter().map(|&byte| char::from(byte)).collect(),
+            Encoding::Windows1252 => bytes
+                .iter()
+                .map(|&byte| match byte {
+                    0x80..=0x9F => WINDOWS_1252_HIGH[usize::from(byte - 0x80)],
+                    _ => char::from(byte),
+                })
+                .collect(),
+        }
+    }
+
+    /// Encodes `text`, replacing characters the charset lacks with `?`.
+    pub fn encode(self, text: &str) -> Cow<'_, [u8]> {
+        match self {
+            Encoding::Utf8 => Cow::Borrowed(text.as_bytes()),
+            _ if text.is_ascii() => Cow::Borrowed(text.as_bytes()),
+            Encoding::Latin1 => Cow::Owned(
+                text.chars()
+                    .map(|ch| u8::try_from(ch).unwrap_or(b'?'))
+                    .collect(),
+            ),
+            Encoding::Windows1252 => Cow::Owned(text.chars().map(encode_windows_1252).collect()),
+        }
+    }
+}
+
+fn encode_windows_1252(ch: char) -> u8 {
+    if let Some(idx) = WINDOWS_1252_HIGH.iter().position(|&high| high == ch) {
+        return 0x80 + idx as u8;
+    }
+    match u8::try_from(ch) {
+        // 0x80-0x9F are taken by the table above.
+        Ok(byte) if !(0x80..=0x9F).contains(&byte) => byte,
+        _ => b'?',
+    }
+}
+
+/// Decodes a line as UTF-8 when it is valid, else with `fallback`.
+pub fn decode_line(bytes: &[u8], fallback: Encoding) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(line) => line.to_string(),
+        Err(_) => fallback.decode(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_utf8_is_never_reinterpreted() {
+        let line = ":a PRIVMSG #c :héllo €".as_bytes();
+        assert_eq!(
+            decode_line(line, Encoding::Latin1),
+            ":a PRIVMSG #c :héllo €"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_falls_back_to_latin1() {
+        // "café" and "£5" as Latin-1.
+        let line = b":a PRIVMSG #c :caf\xe9 \xa35";
+        assert_eq!(
+            decode_line(line, Encoding::Latin1),
+            ":a PRIVMSG #c :café £5"
+        );
+        // Utf8 as the fallback keeps the line, with replacement chars.
+        assert_eq!(
+            decode_line(line, Encoding::Utf8),
+            ":a PRIVMSG #c :caf\u{FFFD} \u{FFFD}5"
+        );
+    }
+
+    #[test]
+    fn windows_1252_reads_smart_quotes_where_latin1_reads_controls() {
+        let bytes = b"\x93quoted\x94 \x80 \x81";
+        assert_eq!(Encoding::Windows1252.decode(bytes), "“quoted” € \u{81}");
+        assert_eq!(
+            Encoding::Latin1.decode(bytes),
+            "\u{93}quoted\u{94} \u{80} \u{81}"
+        );
+    }
+
+    #[test]
+    fn every_byte_round_trips_through_the_single_byte_charsets() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for encoding in [Encoding::Latin1, Encoding::Windows1252] {
+            let text = encoding.decode(&bytes);
+            assert_eq!(encoding.encode(&text), bytes.as_slice(), "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn unmappable_chars_encode_as_question_marks() {
+        assert_eq!(&*Encoding::Latin1.encode("café €"), b"caf\xe9 ?");
+        assert_eq!(
+            &*Encoding::Windows1252.encode("café € ✓"),
+            b"caf\xe9 \x80 ?"
+        );
+        // C1 controls aren't in windows-1252's printable range.
+        assert_eq!(&*Encoding::Windows1252.encode("\u{93}"), b"?");
+        assert_eq!(&*Encoding::Utf8.encode("€"), "€".as_bytes());
+    }
+
+    #[test]
+    fn ascii_is_borrowed_in_every_charset() {
+        for encoding in [Encoding::Utf8, Encoding::Latin1, Encoding::Windows1252] {
+            assert!(matches!(
+                encoding.encode("PRIVMSG #c :hi"),
+                Cow::Borrowed(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn serializes_as_charset_names() {
+        assert_eq!(
+            serde_json::to_value(Encoding::Windows1252).unwrap(),
+            "windows-1252"
+        );
+        let encoding: Encoding = serde_json::from_value("iso-8859-1".into()).unwrap();
+        assert_eq!(encoding, Encoding::Latin1);
+    }
+}
@@ -0,0 +1,30 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`. Only built with the
+//! `fuzzing` feature.
+
+use crate::parser::{Message, MAX_PARAMS};
+
+pub use crate::connection::fuzz_handle_lines as handle_lines;
+
+/// Parses `line`, checks the shape of the result and reads every part of it.
+pub fn parse_message(line: &str) {
+    let message = Message::parse(line);
+    assert!(!message.command.contains(' '));
+    assert!(message.params.len() < MAX_PARAMS);
+    for param in message.params.iter() {
+        assert!(!param.is_empty() && !param.contains(' ') && !param.starts_with(':'));
+    }
+    assert_eq!(
+        message.all_params().count(),
+        message.params.len() + usize::from(message.trailing.is_some())
+    );
+    for idx in 0..=MAX_PARAMS {
+        let _ = message.param(idx);
+    }
+    let _ = message.nick();
+    if let Some(prefix) = message.prefix {
+        let _ = prefix.userhost();
+    }
+    let _ = message.tags.is_empty();
+    let _ = message.tags.get("time");
+    let _ = message.tags.to_map();
+}
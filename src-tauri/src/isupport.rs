@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Nickname and channel casemapping advertised with `CASEMAPPING`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseMapping {
+    Ascii,
+    /// ASCII plus `[]\~` as the uppercase forms of `{}|^`.
+    #[default]
+    Rfc1459,
+    /// Like `rfc1459` but without the `~`/`^` pair.
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    fn parse(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "ascii" => CaseMapping::Ascii,
+            "strict-rfc1459" => CaseMapping::StrictRfc1459,
+            // rfc7613 and unknown mappings fold at least like rfc1459.
+            _ => CaseMapping::Rfc1459,
+        }
+    }
+
+    pub fn fold_char(self, ch: char) -> char {
+        match (self, ch) {
+            (_, 'A'..='Z') => ch.to_ascii_lowercase(),
+            (CaseMapping::Ascii, _) => ch,
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => ch,
+        }
+    }
+
+    pub fn fold(self, input: &str) -> String {
+        input.chars().map(|ch| self.fold_char(ch)).collect()
+    }
+
+    pub fn equals(self, a: &str, b: &str) -> bool {
+        a.chars().count() == b.chars().count()
+            && a.chars()
+                .zip(b.chars())
+                .all(|(x, y)| self.fold_char(x) == self.fold_char(y))
+    }
+}
+
+/// `CHANMODES` split into its four classes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelModes {
+    /// Type A: list modes (`b`, `e`, `I`), always take a parameter.
+    pub list: String,
+    /// Type B: always take a parameter (`k`).
+    pub always_param: String,
+    /// Type C: take a parameter only when set (`l`).
+    pub set_param: String,
+    /// Type D: never take a parameter.
+    pub no_param: String,
+}
+
+impl Default for ChannelModes {
+    fn default() -> Self {
+        Self {
+            list: "beI".into(),
+            always_param: "k".into(),
+            set_param: "l".into(),
+            no_param: "imnpst".into(),
+        }
+    }
+}
+
+/// Per-connection server features from `RPL_ISUPPORT` (005), starting from
+/// the usual defaults until the server says otherwise.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub network: Option<String>,
+    pub casemapping: CaseMapping,
+    /// `(mode, prefix)` pairs from `PREFIX`, highest rank first.
+    pub prefixes: Vec<(char, char)>,
+    pub chantypes: String,
+    pub chanmodes: ChannelModes,
+    pub statusmsg: String,
+    pub nicklen: Option<usize>,
+    pub channellen: Option<usize>,
+    pub topiclen: Option<usize>,
+    /// Maximum line length including the trailing CRLF.
+    pub linelen: usize,
+    /// Per-command target limits from `TARGMAX`; `None` means unlimited.
+    pub targmax: BTreeMap<String, Option<usize>>,
+    /// Every token as advertised, after unescaping.
+    pub tokens: BTreeMap<String, Option<String>>,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self {
+            network: None,
+            casemapping: CaseMapping::default(),
+            prefixes: vec![('o', '@'), ('v', '+')],
+            chantypes: "#&".into(),
+            chanmodes: ChannelModes::default(),
+            statusmsg: String::new(),
+            nicklen: None,
+            channellen: None,
+            topiclen: None,
+            linelen: 512,
+            targmax: BTreeMap::new(),
+            tokens: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerInfo {
+    /// Applies the tokens of one 005 line (the parameters between our nick and
+    /// the trailing "are supported by this server").
+    pub fn apply<S: AsRef<str>>(&mut self, tokens: &[S]) {
+        for token in tokens {
+            let token = token.as_ref();
+            if let Some(name) = token.strip_prefix('-') {
+                self.tokens.remove(name);
+                self.reset(name);
+                continue;
+            }
+            let (name, value) = match token.split_once('=') {
+                Some((name, value)) => (name, Some(unescape_value(value))),
+                None => (token, None),
+            };
+            if name.is_empty() {
+                continue;
+            }
+            self.set(name, value.as_deref());
+            self.tokens.insert(name.to_string(), value);
+        }
+    }
+
+    fn set(&mut self, name: &str, value: Option<&str>) {
+        let value = value.unwrap_or_default();
+        match name {
+            "NETWORK" => self.network = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "CASEMAPPING" => self.casemapping = CaseMapping::parse(value),
+            "PREFIX" => self.prefixes = parse_prefix(value),
+            "CHANTYPES" => self.chantypes = value.to_string(),
+            "CHANMODES" => {
+                let mut classes = value.split(',').map(str::to_string);
+                self.chanmodes = ChannelModes {
+                    list: classes.next().unwrap_or_default(),
+                    always_param: classes.next().unwrap_or_default(),
+                    set_param: classes.next().unwrap_or_default(),
+                    no_param: classes.next().unwrap_or_default(),
+                };
+            }
+            "STATUSMSG" => self.statusmsg = value.to_string(),
+            "NICKLEN" | "MAXNICKLEN" => self.nicklen = value.parse().ok(),
+            "CHANNELLEN" => self.channellen = value.parse().ok(),
+            "TOPICLEN" => self.topiclen = value.parse().ok(),
+            "LINELEN" => self.linelen = value.parse().ok().filter(|len| *len >= 512).unwrap_or(512),
+            "TARGMAX" => {
+                self.targmax = value
+                    .split(',')
+                    .filter_map(|entry| {
+                        let (command, limit) = entry.split_once(':')?;
+                        Some((command.to_ascii_uppercase(), limit.parse().ok()))
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self, name: &str) {
+        let defaults = ServerInfo::default();
+        match name {
+            "NETWORK" => self.network = defaults.network,
+            "CASEMAPPING" => self.casemapping = defaults.casemapping,
+            "PREFIX" => self.prefixes = defaults.prefixes,
+            "CHANTYPES" => self.chantypes = defaults.chantypes,
+            "CHANMODES" => self.chanmodes = defaults.chanmodes,
+            "STATUSMSG" => self.statusmsg = defaults.statusmsg,
+            "NICKLEN" | "MAXNICKLEN" => self.nicklen = defaults.nicklen,
+            "CHANNELLEN" => self.channellen = defaults.channellen,
+            "TOPICLEN" => self.topiclen = defaults.topiclen,
+            "LINELEN" => self.linelen = defaults.linelen,
+            "TARGMAX" => self.targmax = defaults.targmax,
+            _ => {}
+        }
+    }
+
+    /// Whether `target` names a channel, including `STATUSMSG` forms such as
+    /// `@#channel`.
+    pub fn is_channel(&self, target: &str) -> bool {
+        let target = target.trim_start_matches(|ch| self.statusmsg.contains(ch));
+        target
+            .chars()
+            .next()
+            .is_some_and(|ch| self.chantypes.contains(ch))
+    }
+
+    /// The membership mode for a `NAMES`/`WHO` prefix symbol such as `@`.
+    pub fn mode_for_prefix(&self, symbol: char) -> Option<char> {
+        self.prefixes
+            .iter()
+            .find(|(_, prefix)| *prefix == symbol)
+            .map(|(mode, _)| *mode)
+    }
+
+    pub fn prefix_for_mode(&self, mode: char) -> Option<char> {
+        self.prefixes
+            .iter()
+            .find(|(prefix_mode, _)| *prefix_mode == mode)
+            .map(|(_, prefix)| *prefix)
+    }
+}
+
+/// The name the UI uses for a membership mode letter.
+pub fn prefix_mode_name(mode: char) -> String {
+    match mode {
+        'q' => "owner".into(),
+        'a' => "admin".into(),
+        'o' => "op".into(),
+        'h' => "halfop".into(),
+        'v' => "voice".into(),
+        other => other.to_string(),
+    }
+}
+
+fn parse_prefix(value: &str) -> Vec<(char, char)> {
+    let Some(rest) = value.strip_prefix('(') else {
+        return Vec::new();
+    };
+    let Some((modes, prefixes)) = rest.split_once(')') else {
+        return Vec::new();
+    };
+    modes.chars().zip(prefixes.chars()).collect()
+}
+
+/// Decodes the `\xHH` escapes allowed in ISUPPORT values.
+fn unescape_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'\\' && bytes.get(idx + 1) == Some(&b'x') {
+            if let Some(byte) = value
+                .get(idx + 2..idx + 4)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                out.push(byte);
+                idx += 4;
+                continue;
+            }
+        }
+        out.push(bytes[idx]);
+        idx += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(tokens: &[&str]) -> ServerInfo {
+        let mut server = ServerInfo::default();
+        server.apply(tokens);
+        server
+    }
+
+    #[test]
+    fn rfc1459_folds_brackets_and_tilde() {
+        let casemapping = CaseMapping::Rfc1459;
+        assert_eq!(casemapping.fold("Nick[Away]\\~"), "nick{away}|^");
+        assert!(casemapping.equals("[foo]~", "{FOO}^"));
+    }
+
+    #[test]
+    fn strict_rfc1459_leaves_tilde_alone() {
+        let casemapping = CaseMapping::StrictRfc1459;
+        assert_eq!(casemapping.fold("Nick[Away]\\~"), "nick{away}|~");
+        assert!(!casemapping.equals("a~", "a^"));
+    }
+
+    #[test]
+    fn ascii_only_folds_letters() {
+        let casemapping = CaseMapping::Ascii;
+        assert_eq!(casemapping.fold("Nick[Away]\\~"), "nick[away]\\~");
+        assert!(casemapping.equals("#Rust", "#rust"));
+        assert!(!casemapping.equals("[a]", "{a}"));
+        // Non-ASCII letters are compared as they are.
+        assert!(!casemapping.equals("É", "é"));
+    }
+
+    #[test]
+    fn equals_needs_the_same_length() {
+        assert!(!CaseMapping::Rfc1459.equals("abc", "ab"));
+        assert!(CaseMapping::Rfc1459.equals("", ""));
+    }
+
+    #[test]
+    fn casemapping_comes_from_005() {
+        assert_eq!(
+            server(&["CASEMAPPING=ascii"]).casemapping,
+            CaseMapping::Ascii
+        );
+        assert_eq!(
+            server(&["CASEMAPPING=strict-rfc1459"]).casemapping,
+            CaseMapping::StrictRfc1459
+        );
+        assert_eq!(
+            server(&["CASEMAPPING=rfc7613"]).casemapping,
+            CaseMapping::Rfc1459
+        );
+        let mut server = server(&["CASEMAPPING=ascii"]);
+        server.apply(&["-CASEMAPPING"]);
+        assert_eq!(server.casemapping, CaseMapping::Rfc1459);
+    }
+
+    #[test]
+    fn defaults_until_005() {
+        let server = ServerInfo::default();
+        assert_eq!(server.casemapping, CaseMapping::Rfc1459);
+        assert_eq!(server.prefixes, [('o', '@'), ('v', '+')]);
+        assert_eq!(server.chantypes, "#&");
+        assert_eq!(server.linelen, 512);
+        assert!(server.tokens.is_empty());
+    }
+
+    #[test]
+    fn applies_common_tokens() {
+        let server = server(&[
+            "NETWORK=Libera.Chat",
+            "PREFIX=(qaohv)~&@%+",
+            "CHANTYPES=#",
+            "CHANMODES=eIbq,k,flj,CFLMPQScgimnprstuz",
+            "STATUSMSG=@+",
+            "NICKLEN=16",
+            "CHANNELLEN=64",
+            "TOPICLEN=390",
+            "TARGMAX=PRIVMSG:4,notice:4,JOIN:",
+            "SAFELIST",
+        ]);
+        assert_eq!(server.network.as_deref(), Some("Libera.Chat"));
+        assert_eq!(
+            server.prefixes,
+            [('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')]
+        );
+        assert_eq!(server.chantypes, "#");
+        assert_eq!(server.chanmodes.list, "eIbq");
+        assert_eq!(server.chanmodes.always_param, "k");
+        assert_eq!(server.chanmodes.set_param, "flj");
+        assert_eq!(server.chanmodes.no_param, "CFLMPQScgimnprstuz");
+        assert_eq!(server.statusmsg, "@+");
+        assert_eq!(server.nicklen, Some(16));
+        assert_eq!(server.channellen, Some(64));
+        assert_eq!(server.topiclen, Some(390));
+        assert_eq!(server.targmax.get("PRIVMSG"), Some(&Some(4)));
+        assert_eq!(server.targmax.get("NOTICE"), Some(&Some(4)));
+        assert_eq!(server.targmax.get("JOIN"), Some(&None));
+        assert_eq!(server.tokens.get("SAFELIST"), Some(&None));
+    }
+
+    #[test]
+    fn linelen_is_never_below_512() {
+        assert_eq!(server(&["LINELEN=1024"]).linelen, 1024);
+        assert_eq!(server(&["LINELEN=100"]).linelen, 512);
+        assert_eq!(server(&["LINELEN=lots"]).linelen, 512);
+    }
+
+    #[test]
+    fn negated_tokens_restore_defaults() {
+        let mut server = server(&["PREFIX=(ov)@+", "CHANTYPES=#", "NICKLEN=9"]);
+        server.apply(&["PREFIX=(qov)~@+", "-CHANTYPES", "-NICKLEN"]);
+        assert_eq!(server.prefixes, [('q', '~'), ('o', '@'), ('v', '+')]);
+        assert_eq!(server.chantypes, "#&");
+        assert_eq!(server.nicklen, None);
+        assert!(!server.tokens.contains_key("CHANTYPES"));
+    }
+
+    #[test]
+    fn unescapes_values() {
+        let server = server(&["NETWORK=Example\\x20Net\\x5cwork", "FOO=\\xZZ"]);
+        assert_eq!(server.network.as_deref(), Some("Example Net\\work"));
+        assert_eq!(server.tokens["FOO"].as_deref(), Some("\\xZZ"));
+    }
+
+    #[test]
+    fn malformed_prefix_means_no_prefixes() {
+        assert!(server(&["PREFIX="]).prefixes.is_empty());
+        assert!(server(&["PREFIX=(ov"]).prefixes.is_empty());
+        assert!(server(&["=oops"]).tokens.is_empty());
+    }
+
+    #[test]
+    fn channels_by_chantypes_and_statusmsg() {
+        let server = server(&["CHANTYPES=#!", "STATUSMSG=@+"]);
+        assert!(server.is_channel("#rust"));
+        assert!(server.is_channel("!abcdefoo"));
+        assert!(server.is_channel("@#rust"));
+        assert!(server.is_channel("+#rust"));
+        assert!(!server.is_channel("&local"));
+        assert!(!server.is_channel("alice"));
+        assert!(!server.is_channel(""));
+    }
+
+    #[test]
+    fn maps_prefixes_to_modes_and_back() {
+        let server = server(&["PREFIX=(qaohv)~&@%+"]);
+        assert_eq!(server.mode_for_prefix('%'), Some('h'));
+        assert_eq!(server.prefix_for_mode('q'), Some('~'));
+        assert_eq!(server.mode_for_prefix('!'), None);
+        assert_eq!(server.prefix_for_mode('x'), None);
+        assert_eq!(prefix_mode_name('h'), "halfop");
+        assert_eq!(prefix_mode_name('Y'), "Y");
+    }
+}
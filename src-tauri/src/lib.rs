@@ -1,13 +1,25 @@
+mod capabilities;
+mod channels;
 mod commands;
 mod config_store;
 mod connection;
+mod ctcp;
+mod encoding;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod isupport;
 mod messages;
+mod outgoing;
+mod parser;
+mod sasl;
+mod send_queue;
 mod state;
 mod storage;
 
 use commands::{
-    irc_connect, irc_disconnect, irc_join, irc_list_connections, irc_part, irc_saved_connections,
-    irc_scrollback, irc_send_message, irc_set_topic,
+    irc_capabilities, irc_channel_state, irc_connect, irc_connection_state, irc_ctcp,
+    irc_disconnect, irc_join, irc_list_connections, irc_part, irc_saved_connections,
+    irc_scrollback, irc_send_action, irc_send_message, irc_server_info, irc_set_topic,
 };
 use config_store::ConfigStore;
 use connection::ConnectionManager;
@@ -44,6 +56,12 @@ pub fn run() {
             irc_scrollback,
             irc_list_connections,
             irc_saved_connections,
+            irc_capabilities,
+            irc_server_info,
+            irc_channel_state,
+            irc_connection_state,
+            irc_ctcp,
+            irc_send_action,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -1,5 +1,9 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::isupport::ServerInfo;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageKind {
@@ -10,6 +14,8 @@ pub enum MessageKind {
     Part,
     Quit,
     Nick,
+    Kick,
+    Mode,
     Topic,
     Info,
     Error,
@@ -52,6 +58,7 @@ pub enum IrcEvent {
     Message {
         data: ChatMessage,
     },
+    /// The complete member list, sent once `RPL_ENDOFNAMES` arrives.
     Names {
         connection_id: String,
         channel: String,
@@ -62,9 +69,57 @@ pub enum IrcEvent {
         channel: String,
         topic: String,
         setter: Option<String>,
+        /// Milliseconds since the epoch.
+        set_at: Option<i64>,
     },
     Error {
         connection_id: String,
         message: String,
     },
+    Capabilities {
+        connection_id: String,
+        enabled: Vec<String>,
+    },
+    Reconnecting {
+        connection_id: String,
+        attempt: u32,
+        delay_ms: u64,
+    },
+    Reconnected {
+        connection_id: String,
+        attempt: u32,
+    },
+    Lag {
+        connection_id: String,
+        lag_ms: u64,
+    },
+    /// Lines waiting behind flood control; 0 once the queue has drained.
+    SendQueue {
+        connection_id: String,
+        pending: usize,
+    },
+    /// A member's prefixes changed.
+    ChannelMember {
+        connection_id: String,
+        channel: String,
+        user: ChannelUserInfo,
+    },
+    /// The channel's own modes (everything but lists and prefixes), after a
+    /// `MODE` change or `RPL_CHANNELMODEIS`.
+    ChannelModes {
+        connection_id: String,
+        channel: String,
+        modes: BTreeMap<char, Option<String>>,
+    },
+    Nick {
+        connection_id: String,
+        old_nick: String,
+        new_nick: String,
+        /// The nick that changed is ours.
+        own: bool,
+    },
+    ServerInfo {
+        connection_id: String,
+        info: ServerInfo,
+    },
 }
@@ -0,0 +1,439 @@
+use crate::isupport::ServerInfo;
+
+/// Room assumed for `user@host` in our prefix until the server shows it to
+/// us: the usual USERLEN and HOSTLEN plus the `@`.
+const UNKNOWN_USERHOST_LEN: usize = 10 + 1 + 63;
+
+/// Bytes left for the text of a `PRIVMSG` to `target` once the server has
+/// prepended our `:nick!user@host ` prefix, within `linelen` (which counts
+/// the CRLF). `overhead` is any wrapping around the text, such as CTCP.
+pub fn privmsg_budget(
+    linelen: usize,
+    nickname: &str,
+    userhost: Option<&str>,
+    target: &str,
+    overhead: usize,
+) -> usize {
+    let userhost_len = userhost.map_or(UNKNOWN_USERHOST_LEN, str::len);
+    let prefix = ":".len() + nickname.len() + "!".len() + userhost_len + " ".len();
+    let command = "PRIVMSG ".len() + target.len() + " :".len();
+    linelen.saturating_sub(2 + prefix + command + overhead)
+}
+
+/// Splits `text` into lines of at most `max_bytes` bytes: first at CR/LF,
+/// then at the last space that fits, else at the last char boundary. Empty
+/// lines are dropped, since an empty PRIVMSG can't be sent.
+pub fn split_message(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut out = Vec::new();
+    for mut rest in text.split(['\r', '\n']) {
+        while rest.len() > max_bytes {
+            let mut cut = max_bytes;
+            while !rest.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            if cut == 0 {
+                // Smaller than one char; send the char on its own rather than loop.
+                cut = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            // Prefer a word boundary unless it would leave a very short line.
+            let space = if rest[cut..].starts_with(' ') {
+                Some(cut)
+            } else {
+                rest[..cut].rfind(' ')
+            };
+            let (head, tail) = match space {
+                Some(space) if space >= cut / 2 => (&rest[..space], &rest[space + 1..]),
+                _ => (&rest[..cut], &rest[cut..]),
+            };
+            if !head.is_empty() {
+                out.push(head.to_string());
+            }
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            out.push(rest.to_string());
+        }
+    }
+    out
+}
+
+/// Why an outgoing command was refused.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum OutgoingError {
+    #[error("{0} contains a NUL, CR or LF character")]
+    ForbiddenChar(&'static str),
+    #[error("{0} is empty")]
+    Empty(&'static str),
+    #[error("{0} can't contain spaces or start with ':'")]
+    InvalidParam(&'static str),
+    #[error("\"{0}\" is not a valid channel name")]
+    InvalidChannel(String),
+    #[error("\"{0}\" is not a valid nickname")]
+    InvalidNick(String),
+    #[error("\"{0}\" is not a valid CTCP command")]
+    InvalidCtcp(String),
+    #[error("{0} is longer than the server allows")]
+    TooLong(&'static str),
+}
+
+impl OutgoingError {
+    /// Stable name for the frontend to match on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutgoingError::ForbiddenChar(_) => "forbiddenChar",
+            OutgoingError::Empty(_) => "empty",
+            OutgoingError::InvalidParam(_) => "invalidParam",
+            OutgoingError::InvalidChannel(_) => "invalidChannel",
+            OutgoingError::InvalidNick(_) => "invalidNick",
+            OutgoingError::InvalidCtcp(_) => "invalidCtcp",
+            OutgoingError::TooLong(_) => "tooLong",
+        }
+    }
+}
+
+fn has_forbidden(text: &str) -> bool {
+    text.contains(['\0', '\r', '\n'])
+}
+
+/// Builds one raw line, refusing values that could end the line early or
+/// shift the parameters after them. The first error sticks until `build`.
+#[derive(Debug)]
+pub struct LineBuilder {
+    line: String,
+    error: Option<OutgoingError>,
+}
+
+impl LineBuilder {
+    pub fn new(command: &str) -> Self {
+        Self {
+            line: command.to_string(),
+            error: None,
+        }
+    }
+
+    /// A middle parameter, such as a channel or nick.
+    pub fn param(mut self, what: &'static str, value: &str) -> Self {
+        if self.error.is_none() {
+            if value.is_empty() {
+                self.error = Some(OutgoingError::Empty(what));
+            } else if has_forbidden(value) {
+                self.error = Some(OutgoingError::ForbiddenChar(what));
+            } else if value.contains(' ') || value.starts_with(':') {
+                self.error = Some(OutgoingError::InvalidParam(what));
+            } else {
+                self.line.push(' ');
+                self.line.push_str(value);
+            }
+        }
+        self
+    }
+
+    /// The final parameter, which may contain spaces.
+    pub fn trailing(mut self, what: &'static str, value: &str) -> Self {
+        if self.error.is_none() {
+            if has_forbidden(value) {
+                self.error = Some(OutgoingError::ForbiddenChar(what));
+            } else {
+                self.line.push_str(" :");
+                self.line.push_str(value);
+            }
+        }
+        self
+    }
+
+    pub fn trailing_opt(self, what: &'static str, value: Option<&str>) -> Self {
+        match value {
+            Some(value) => self.trailing(what, value),
+            None => self,
+        }
+    }
+
+    pub fn build(self) -> Result<String, OutgoingError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.line),
+        }
+    }
+}
+
+/// Checks a single channel name against `CHANTYPES` and `CHANNELLEN`.
+pub fn check_channel(server: &ServerInfo, channel: &str) -> Result<(), OutgoingError> {
+    if channel.is_empty() {
+        return Err(OutgoingError::Empty("channel"));
+    }
+    if has_forbidden(channel) {
+        return Err(OutgoingError::ForbiddenChar("channel"));
+    }
+    if !channel.starts_with(|ch| server.chantypes.contains(ch))
+        || channel.contains([' ', ',', '\u{7}'])
+    {
+        return Err(OutgoingError::InvalidChannel(channel.to_string()));
+    }
+    if server.channellen.is_some_and(|len| channel.len() > len) {
+        return Err(OutgoingError::TooLong("channel"));
+    }
+    Ok(())
+}
+
+/// Checks a nickname: no characters that are special in masks or target
+/// lists, and nothing that could be read as a channel or prefix.
+pub fn check_nick(server: &ServerInfo, nick: &str) -> Result<(), OutgoingError> {
+    let Some(first) = nick.chars().next() else {
+        return Err(OutgoingError::Empty("nickname"));
+    };
+    if has_forbidden(nick) {
+        return Err(OutgoingError::ForbiddenChar("nickname"));
+    }
+    let starts_badly = first.is_ascii_digit()
+        || matches!(first, '-' | '$' | ':')
+        || server.chantypes.contains(first)
+        || server.mode_for_prefix(first).is_some();
+    if starts_badly || nick.contains([' ', ',', '*', '?', '!', '@', '.']) {
+        return Err(OutgoingError::InvalidNick(nick.to_string()));
+    }
+    if server.nicklen.is_some_and(|len| nick.len() > len) {
+        return Err(OutgoingError::TooLong("nickname"));
+    }
+    Ok(())
+}
+
+/// Checks a message target: a channel, possibly with a `STATUSMSG` prefix
+/// such as `@#channel`, or a nick.
+pub fn check_target(server: &ServerInfo, target: &str) -> Result<(), OutgoingError> {
+    if server.is_channel(target) {
+        check_channel(
+            server,
+            target.trim_start_matches(|ch| server.statusmsg.contains(ch)),
+        )
+    } else {
+        check_nick(server, target)
+    }
+}
+
+/// Checks free text such as a topic or a part reason.
+pub fn check_text(what: &'static str, text: &str) -> Result<(), OutgoingError> {
+    if has_forbidden(text) {
+        return Err(OutgoingError::ForbiddenChar(what));
+    }
+    Ok(())
+}
+
+/// Checks message text, where line breaks are fine (they split the message)
+/// but NUL is not.
+pub fn check_message(text: &str) -> Result<(), OutgoingError> {
+    if text.contains('\0') {
+        return Err(OutgoingError::ForbiddenChar("message"));
+    }
+    if split_message(text, usize::MAX).is_empty() {
+        return Err(OutgoingError::Empty("message"));
+    }
+    Ok(())
+}
+
+/// Checks a CTCP query: an alphanumeric command and params that can't break
+/// out of the `\x01` framing.
+pub fn check_ctcp(command: &str, params: Option<&str>) -> Result<(), OutgoingError> {
+    if command.is_empty() || !command.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+        return Err(OutgoingError::InvalidCtcp(command.to_string()));
+    }
+    let params = params.unwrap_or_default();
+    check_text("CTCP params", params)?;
+    if params.contains('\u{1}') {
+        return Err(OutgoingError::InvalidCtcp(command.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_fills_the_line_exactly() {
+        let budget = privmsg_budget(512, "nick", Some("user@host"), "#chan", 0);
+        let line = format!(":nick!user@host PRIVMSG #chan :{}\r\n", "x".repeat(budget));
+        assert_eq!(line.len(), 512);
+    }
+
+    #[test]
+    fn budget_assumes_a_long_userhost_until_known() {
+        let known = privmsg_budget(512, "nick", Some("u@h"), "#chan", 0);
+        let unknown = privmsg_budget(512, "nick", None, "#chan", 0);
+        assert_eq!(known - unknown, UNKNOWN_USERHOST_LEN - "u@h".len());
+        assert_eq!(privmsg_budget(20, "nick", None, "#chan", 0), 0);
+    }
+
+    #[test]
+    fn splits_at_the_last_space_that_fits() {
+        assert_eq!(
+            split_message("hello world foo bar", 11),
+            ["hello world", "foo bar"]
+        );
+    }
+
+    #[test]
+    fn splits_a_word_longer_than_the_budget() {
+        let word = "a".repeat(25);
+        assert_eq!(
+            split_message(&word, 10),
+            ["a".repeat(10), "a".repeat(10), "a".repeat(5)]
+        );
+        // A space too early in the line isn't worth a near-empty line.
+        assert_eq!(split_message("a bbbbbbbbbbbb", 10), ["a bbbbbbbb", "bbbb"]);
+    }
+
+    #[test]
+    fn never_splits_inside_a_utf8_char() {
+        // 'é' is two bytes and '€' three, so byte 10 falls mid-char.
+        let text = "éééé€€€€";
+        let lines = split_message(text, 10);
+        assert_eq!(lines, ["éééé", "€€€", "€"]);
+        assert!(lines.iter().all(|line| line.len() <= 10));
+        assert_eq!(lines.concat(), text);
+        // A budget smaller than one char still makes progress.
+        assert_eq!(split_message("€€", 2), ["€", "€"]);
+    }
+
+    #[test]
+    fn line_breaks_start_new_lines_and_blank_ones_are_dropped() {
+        assert_eq!(
+            split_message("one\r\ntwo\n\nthree\r", 100),
+            ["one", "two", "three"]
+        );
+    }
+
+    #[test]
+    fn empty_message_yields_no_lines() {
+        assert!(split_message("", 10).is_empty());
+        assert!(split_message("\r\n\n", 10).is_empty());
+        assert_eq!(check_message(""), Err(OutgoingError::Empty("message")));
+        assert_eq!(check_message("\r\n"), Err(OutgoingError::Empty("message")));
+        assert_eq!(check_message("hi\nthere"), Ok(()));
+    }
+
+    #[test]
+    fn builder_rejects_line_breaks_and_nul_anywhere() {
+        for bad in ["a\rQUIT", "a\nQUIT", "a\0"] {
+            assert_eq!(
+                LineBuilder::new("JOIN").param("channel", bad).build(),
+                Err(OutgoingError::ForbiddenChar("channel")),
+                "{bad:?}"
+            );
+            assert_eq!(
+                LineBuilder::new("PART")
+                    .param("channel", "#a")
+                    .trailing("part reason", bad)
+                    .build(),
+                Err(OutgoingError::ForbiddenChar("part reason")),
+                "{bad:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn builder_keeps_middle_params_to_one_word() {
+        let build = |value| LineBuilder::new("MODE").param("target", value).build();
+        assert_eq!(build(""), Err(OutgoingError::Empty("target")));
+        assert_eq!(build("#a b"), Err(OutgoingError::InvalidParam("target")));
+        assert_eq!(build(":#a"), Err(OutgoingError::InvalidParam("target")));
+        assert_eq!(build("#a"), Ok("MODE #a".to_string()));
+    }
+
+    #[test]
+    fn builder_reports_the_first_error() {
+        let line = LineBuilder::new("PRIVMSG")
+            .param("target", "")
+            .trailing("message", "a\nb")
+            .build();
+        assert_eq!(line, Err(OutgoingError::Empty("target")));
+        let line = LineBuilder::new("TOPIC")
+            .param("channel", "#a")
+            .trailing_opt("topic", Some(""))
+            .build();
+        assert_eq!(line, Ok("TOPIC #a :".to_string()));
+    }
+
+    #[test]
+    fn checks_channels_against_isupport() {
+        let mut server = ServerInfo::default();
+        assert_eq!(check_channel(&server, "#ok"), Ok(()));
+        assert_eq!(check_channel(&server, "&ok"), Ok(()));
+        for bad in ["nochan", "#a b", "#a,#b", "#a\u{7}"] {
+            assert_eq!(
+                check_channel(&server, bad),
+                Err(OutgoingError::InvalidChannel(bad.to_string()))
+            );
+        }
+        assert_eq!(
+            check_channel(&server, "#a\r\nQUIT"),
+            Err(OutgoingError::ForbiddenChar("channel"))
+        );
+        server.apply(&["CHANTYPES=#", "CHANNELLEN=5"]);
+        assert!(check_channel(&server, "&ok").is_err());
+        assert_eq!(
+            check_channel(&server, "#toolong"),
+            Err(OutgoingError::TooLong("channel"))
+        );
+    }
+
+    #[test]
+    fn checks_nicks() {
+        let mut server = ServerInfo::default();
+        assert_eq!(check_nick(&server, "fluxchat"), Ok(()));
+        assert_eq!(check_nick(&server, "[away]"), Ok(()));
+        for bad in ["1st", "-a", "#a", "@op", "+v", "a b", "a,b", "a!b", "a@b"] {
+            assert_eq!(
+                check_nick(&server, bad),
+                Err(OutgoingError::InvalidNick(bad.to_string())),
+                "{bad}"
+            );
+        }
+        assert_eq!(
+            check_nick(&server, "a\nQUIT"),
+            Err(OutgoingError::ForbiddenChar("nickname"))
+        );
+        server.apply(&["NICKLEN=4"]);
+        assert_eq!(
+            check_nick(&server, "fluxchat"),
+            Err(OutgoingError::TooLong("nickname"))
+        );
+    }
+
+    #[test]
+    fn targets_may_carry_a_statusmsg_prefix() {
+        let mut server = ServerInfo::default();
+        server.apply(&["STATUSMSG=@+"]);
+        assert_eq!(check_target(&server, "@#ops"), Ok(()));
+        assert_eq!(check_target(&server, "alice"), Ok(()));
+        assert!(check_target(&server, "a b").is_err());
+    }
+
+    #[test]
+    fn ctcp_params_cant_break_the_framing() {
+        assert_eq!(check_ctcp("PING", Some("123")), Ok(()));
+        assert_eq!(check_ctcp("VERSION", None), Ok(()));
+        for (command, params) in [("", None), ("PI NG", None), ("PING", Some("a\u{1}b"))] {
+            assert!(matches!(
+                check_ctcp(command, params),
+                Err(OutgoingError::InvalidCtcp(_))
+            ));
+        }
+        assert_eq!(
+            check_ctcp("PING", Some("a\r\n")),
+            Err(OutgoingError::ForbiddenChar("CTCP params"))
+        );
+    }
+
+    #[test]
+    fn free_text_rejects_line_breaks_but_messages_split_on_them() {
+        assert_eq!(
+            check_text("topic", "a\rb"),
+            Err(OutgoingError::ForbiddenChar("topic"))
+        );
+        assert_eq!(check_message("a\r\nb"), Ok(()));
+        assert_eq!(
+            check_message("a\0b"),
+            Err(OutgoingError::ForbiddenChar("message"))
+        );
+    }
+}
@@ -0,0 +1,503 @@
+use std::{borrow::Cow, collections::HashMap, ops::Deref};
+
+/// Most params a message can carry; past the 14th middle param, the rest of
+/// the line is the last one even without a `:`.
+pub const MAX_PARAMS: usize = 15;
+
+/// One IRC line, borrowing from the input. `params` holds the middle params;
+/// the param introduced by `:` (or the 15th) is `trailing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub tags: Tags<'a>,
+    pub prefix: Option<Prefix<'a>>,
+    pub command: &'a str,
+    pub params: Params<'a>,
+    pub trailing: Option<&'a str>,
+}
+
+impl<'a> Message<'a> {
+    /// Parses a line with or without its CRLF. Lines without a command parse
+    /// with an empty `command`, which no handler matches.
+    pub fn parse(line: &'a str) -> Self {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut rest = line.trim_start_matches(' ');
+
+        let mut tags = Tags::default();
+        if let Some(after) = rest.strip_prefix('@') {
+            let (raw, tail) = split_word(after);
+            tags = Tags(raw);
+            rest = tail;
+        }
+
+        let mut prefix = None;
+        if let Some(after) = rest.strip_prefix(':') {
+            let (raw, tail) = split_word(after);
+            prefix = Some(Prefix::parse(raw));
+            rest = tail;
+        }
+
+        let (command, mut rest) = split_word(rest);
+        let mut params = Params::default();
+        let mut trailing = None;
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(last) = rest.strip_prefix(':') {
+                trailing = Some(last);
+                break;
+            }
+            if params.len == MAX_PARAMS - 1 {
+                trailing = Some(rest);
+                break;
+            }
+            let (param, tail) = split_word(rest);
+            params.items[params.len] = param;
+            params.len += 1;
+            rest = tail;
+        }
+
+        Message {
+            tags,
+            prefix,
+            command,
+            params,
+            trailing,
+        }
+    }
+
+    /// The nick from the prefix, if there is one.
+    pub fn nick(&self) -> Option<&'a str> {
+        self.prefix
+            .map(|prefix| prefix.nick)
+            .filter(|nick| !nick.is_empty())
+    }
+
+    /// Param `idx`, counting `trailing` as the last one.
+    pub fn param(&self, idx: usize) -> Option<&'a str> {
+        match self.params.get(idx) {
+            Some(param) => Some(param),
+            None if idx == self.params.len() => self.trailing,
+            None => None,
+        }
+    }
+
+    /// Every param, with `trailing` last.
+    pub fn all_params(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.params.iter().copied().chain(self.trailing)
+    }
+}
+
+/// Splits at the first space: the word before it and everything after.
+fn split_word(input: &str) -> (&str, &str) {
+    input.split_once(' ').unwrap_or((input, ""))
+}
+
+/// The middle params of a message; derefs to a slice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Params<'a> {
+    items: [&'a str; MAX_PARAMS - 1],
+    len: usize,
+}
+
+impl<'a> Deref for Params<'a> {
+    type Target = [&'a str];
+
+    fn deref(&self) -> &Self::Target {
+        &self.items[..self.len]
+    }
+}
+
+/// `nick!user@host`, or a server name (which parses as a bare nick).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix<'a> {
+    pub raw: &'a str,
+    pub nick: &'a str,
+    pub user: Option<&'a str>,
+    pub host: Option<&'a str>,
+}
+
+impl<'a> Prefix<'a> {
+    pub fn parse(raw: &'a str) -> Self {
+        let (rest, host) = match raw.split_once('@') {
+            Some((rest, host)) => (rest, Some(host)),
+            None => (raw, None),
+        };
+        let (nick, user) = match rest.split_once('!') {
+            Some((nick, user)) => (nick, Some(user)),
+            None => (rest, None),
+        };
+        Prefix {
+            raw,
+            nick,
+            user,
+            host,
+        }
+    }
+
+    /// `user@host`, when both are present.
+    pub fn userhost(&self) -> Option<&'a str> {
+        self.user?;
+        self.host?;
+        self.raw.split_once('!').map(|(_, userhost)| userhost)
+    }
+}
+
+/// The raw IRCv3 tag section, without the `@`. Values are unescaped on
+/// access, so lines without escapes never allocate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tags<'a>(&'a str);
+
+impl<'a> Tags<'a> {
+    pub fn is_empty(self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Tags in line order; a key can repeat, and the last one wins.
+    pub fn iter(self) -> impl Iterator<Item = (&'a str, Cow<'a, str>)> {
+        self.0.split(';').filter_map(|item| {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            (!key.is_empty()).then(|| (key, unescape_tag_value(value)))
+        })
+    }
+
+    pub fn get(self, key: &str) -> Option<Cow<'a, str>> {
+        self.iter()
+            .filter(|(name, _)| *name == key)
+            .last()
+            .map(|(_, value)| value)
+    }
+
+    pub fn to_map(self) -> HashMap<String, String> {
+        self.iter()
+            .map(|(key, value)| (key.to_string(), value.into_owned()))
+            .collect()
+    }
+}
+
+fn unescape_tag_value(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        // A lone trailing backslash is dropped.
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('\\') => out.push('\\'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params<'a>(message: &Message<'a>) -> Vec<&'a str> {
+        message.all_params().collect()
+    }
+
+    fn tags(message: &Message<'_>) -> Vec<(String, String)> {
+        let mut tags: Vec<_> = message.tags.to_map().into_iter().collect();
+        tags.sort();
+        tags
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut pairs: Vec<_> = expected
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    // Cases from the msg-split section of irc-parser-tests.
+
+    #[test]
+    fn splits_simple_params() {
+        let message = Message::parse("foo bar baz asdf");
+        assert_eq!(message.command, "foo");
+        assert_eq!(params(&message), ["bar", "baz", "asdf"]);
+        assert!(message.prefix.is_none());
+        assert!(message.tags.is_empty());
+    }
+
+    #[test]
+    fn parses_source() {
+        let message = Message::parse(":coolguy foo bar baz asdf");
+        assert_eq!(message.prefix.map(|p| p.raw), Some("coolguy"));
+        assert_eq!(message.command, "foo");
+        assert_eq!(params(&message), ["bar", "baz", "asdf"]);
+    }
+
+    #[test]
+    fn parses_trailing() {
+        let cases: &[(&str, &[&str])] = &[
+            ("foo bar baz :asdf quux", &["bar", "baz", "asdf quux"]),
+            ("foo bar baz :", &["bar", "baz", ""]),
+            ("foo bar baz ::asdf", &["bar", "baz", ":asdf"]),
+            (
+                ":coolguy foo bar baz :asdf quux",
+                &["bar", "baz", "asdf quux"],
+            ),
+            (
+                ":coolguy foo bar baz :  asdf quux ",
+                &["bar", "baz", "  asdf quux "],
+            ),
+            (":coolguy PRIVMSG bar :lol :) ", &["bar", "lol :) "]),
+            (":coolguy foo bar baz :", &["bar", "baz", ""]),
+            (":coolguy foo bar baz :  ", &["bar", "baz", "  "]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(params(&Message::parse(line)), *expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn trailing_is_kept_apart_from_middle_params() {
+        let message = Message::parse("foo bar baz :asdf quux");
+        assert_eq!(&*message.params, ["bar", "baz"]);
+        assert_eq!(message.trailing, Some("asdf quux"));
+        assert_eq!(message.param(2), Some("asdf quux"));
+        assert_eq!(message.param(3), None);
+    }
+
+    #[test]
+    fn colon_inside_a_middle_param_is_not_trailing() {
+        let message = Message::parse(":irc.example.com 005 nick a:b c=d:e :are supported");
+        assert_eq!(&*message.params, ["nick", "a:b", "c=d:e"]);
+        assert_eq!(message.trailing, Some("are supported"));
+    }
+
+    #[test]
+    fn parses_tags() {
+        let message = Message::parse("@a=b;c=32;k;rt=ql7 foo");
+        assert_eq!(message.command, "foo");
+        assert_eq!(
+            tags(&message),
+            pairs(&[("a", "b"), ("c", "32"), ("k", ""), ("rt", "ql7")])
+        );
+    }
+
+    #[test]
+    fn unescapes_tag_values() {
+        let message = Message::parse("@a=b\\\\and\\nk;c=72\\s45;d=gh\\:764 foo");
+        assert_eq!(
+            tags(&message),
+            pairs(&[("a", "b\\and\nk"), ("c", "72 45"), ("d", "gh;764")])
+        );
+
+        let message = Message::parse("@foo=\\\\\\\\\\:\\\\s\\s\\r\\n COMMAND");
+        assert_eq!(message.tags.get("foo").as_deref(), Some("\\\\;\\s \r\n"));
+    }
+
+    #[test]
+    fn tags_with_source_and_params() {
+        let message = Message::parse("@c;h=;a=b :quux ab cd");
+        assert_eq!(tags(&message), pairs(&[("c", ""), ("h", ""), ("a", "b")]));
+        assert_eq!(message.prefix.map(|p| p.raw), Some("quux"));
+        assert_eq!(message.command, "ab");
+        assert_eq!(params(&message), ["cd"]);
+    }
+
+    #[test]
+    fn last_param_with_or_without_colon() {
+        for line in [":src JOIN #chan", ":src JOIN :#chan"] {
+            let message = Message::parse(line);
+            assert_eq!(message.command, "JOIN");
+            assert_eq!(params(&message), ["#chan"], "{line}");
+        }
+    }
+
+    #[test]
+    fn no_params() {
+        for line in [":src AWAY", ":src AWAY ", "COMMAND"] {
+            let message = Message::parse(line);
+            assert!(params(&message).is_empty(), "{line}");
+        }
+    }
+
+    #[test]
+    fn control_codes_in_source() {
+        let cases = [
+            (":cool\tguy foo bar baz", "cool\tguy"),
+            (
+                ":coolguy!ag@net\u{3}5w\u{3}ork.admin PRIVMSG foo :bar baz",
+                "coolguy!ag@net\u{3}5w\u{3}ork.admin",
+            ),
+            (
+                ":coolguy!~ag@n\u{2}et\u{3}05w\u{f}ork.admin PRIVMSG foo :bar baz",
+                "coolguy!~ag@n\u{2}et\u{3}05w\u{f}ork.admin",
+            ),
+        ];
+        for (line, source) in cases {
+            assert_eq!(Message::parse(line).prefix.map(|p| p.raw), Some(source));
+        }
+    }
+
+    #[test]
+    fn vendor_tags() {
+        let expected = pairs(&[
+            ("tag1", "value1"),
+            ("tag2", ""),
+            ("vendor1/tag3", "value2"),
+            ("vendor2/tag4", ""),
+        ]);
+        let message = Message::parse(
+            "@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4= :irc.example.com COMMAND param1 param2 :param3 param3",
+        );
+        assert_eq!(tags(&message), expected);
+        assert_eq!(message.prefix.map(|p| p.raw), Some("irc.example.com"));
+        assert_eq!(params(&message), ["param1", "param2", "param3 param3"]);
+
+        let message = Message::parse(
+            "@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4 COMMAND param1 param2 :param3 param3",
+        );
+        assert_eq!(tags(&message), expected);
+        assert!(message.prefix.is_none());
+        assert_eq!(message.command, "COMMAND");
+    }
+
+    #[test]
+    fn broken_tag_escapes() {
+        let cases = [
+            ("@tag1=value\\\\ntest COMMAND", "value\\ntest"),
+            ("@tag1=value\\1 COMMAND", "value1"),
+            ("@tag1=value1\\ COMMAND", "value1"),
+        ];
+        for (line, value) in cases {
+            let message = Message::parse(line);
+            assert_eq!(message.tags.get("tag1").as_deref(), Some(value), "{line}");
+        }
+    }
+
+    #[test]
+    fn duplicate_tags_last_wins() {
+        let message = Message::parse("@tag1=1;tag2=3;tag3=4;tag1=5;vendor/tag2=8 COMMAND");
+        assert_eq!(
+            tags(&message),
+            pairs(&[
+                ("tag1", "5"),
+                ("tag2", "3"),
+                ("tag3", "4"),
+                ("vendor/tag2", "8")
+            ])
+        );
+    }
+
+    #[test]
+    fn extra_spaces_between_params() {
+        let cases: &[(&str, &[&str])] = &[
+            (
+                ":gravel.mozilla.org 432  #momo :Erroneous Nickname: Illegal characters",
+                &["#momo", "Erroneous Nickname: Illegal characters"],
+            ),
+            (":gravel.mozilla.org MODE #tckk +n ", &["#tckk", "+n"]),
+            (
+                ":services.esper.net MODE #foo-bar +o foobar  ",
+                &["#foo-bar", "+o", "foobar"],
+            ),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(params(&Message::parse(line)), *expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn mode_strings() {
+        let message = Message::parse(":SomeOp MODE #channel :+i");
+        assert_eq!(params(&message), ["#channel", "+i"]);
+        let message = Message::parse(":SomeOp MODE #channel +oo SomeUser :AnotherUser");
+        assert_eq!(
+            params(&message),
+            ["#channel", "+oo", "SomeUser", "AnotherUser"]
+        );
+    }
+
+    #[test]
+    fn fifteenth_param_takes_the_rest() {
+        let message = Message::parse("CMD 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16");
+        assert_eq!(message.params.len(), 14);
+        assert_eq!(message.trailing, Some("15 16"));
+    }
+
+    #[test]
+    fn strips_line_ending() {
+        let message = Message::parse("PING :token\r\n");
+        assert_eq!(message.command, "PING");
+        assert_eq!(message.trailing, Some("token"));
+    }
+
+    #[test]
+    fn tolerates_truncated_lines() {
+        for line in ["", "@", "@a=b", ":", ":src", "@a :src", " "] {
+            let message = Message::parse(line);
+            assert_eq!(message.command, "", "{line:?}");
+            assert!(params(&message).is_empty(), "{line:?}");
+        }
+    }
+
+    // Cases from the userhost-split section of irc-parser-tests.
+
+    #[test]
+    fn splits_prefixes() {
+        let cases: &[(&str, &str, Option<&str>, Option<&str>)] = &[
+            ("coolguy", "coolguy", None, None),
+            (
+                "coolguy!ag@127.0.0.1",
+                "coolguy",
+                Some("ag"),
+                Some("127.0.0.1"),
+            ),
+            (
+                "coolguy!~ag@localhost",
+                "coolguy",
+                Some("~ag"),
+                Some("localhost"),
+            ),
+            ("coolguy@127.0.0.1", "coolguy", None, Some("127.0.0.1")),
+            ("coolguy!ag", "coolguy", Some("ag"), None),
+            (
+                "coolguy!ag@net\u{3}5w\u{3}ork.admin",
+                "coolguy",
+                Some("ag"),
+                Some("net\u{3}5w\u{3}ork.admin"),
+            ),
+            (
+                "coolguy!~ag@n\u{2}et\u{3}05w\u{f}ork.admin",
+                "coolguy",
+                Some("~ag"),
+                Some("n\u{2}et\u{3}05w\u{f}ork.admin"),
+            ),
+        ];
+        for (raw, nick, user, host) in cases {
+            let prefix = Prefix::parse(raw);
+            assert_eq!(prefix.nick, *nick, "{raw}");
+            assert_eq!(prefix.user, *user, "{raw}");
+            assert_eq!(prefix.host, *host, "{raw}");
+        }
+    }
+
+    #[test]
+    fn userhost_needs_both_parts() {
+        assert_eq!(
+            Prefix::parse("nick!~user@host").userhost(),
+            Some("~user@host")
+        );
+        assert_eq!(Prefix::parse("nick!user").userhost(), None);
+        assert_eq!(Prefix::parse("irc.example.com").userhost(), None);
+    }
+}
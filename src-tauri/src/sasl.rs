@@ -0,0 +1,467 @@
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// `AUTHENTICATE` payloads are split into chunks of this many bytes.
+const CHUNK_SIZE: usize = 400;
+
+/// Upper bound on the PBKDF2 iteration count a server may ask for, so a
+/// hostile server cannot keep a blocking thread busy for long.
+const MAX_SCRAM_ITERATIONS: u32 = 1_000_000;
+
+/// How a connection authenticates to its network.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(
+    tag = "mode",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum AuthMode {
+    /// No SASL; only the server `password` (sent as `PASS`) is used, if any.
+    #[default]
+    None,
+    SaslPlain {
+        account: String,
+        password: String,
+    },
+    /// Authenticates with the TLS client certificate (CertFP).
+    SaslExternal,
+    /// SCRAM-SHA-256, or SCRAM-SHA-1 when that is all the server offers. The
+    /// password itself is never sent.
+    SaslScram {
+        account: String,
+        password: String,
+    },
+}
+
+impl AuthMode {
+    pub fn uses_sasl(&self) -> bool {
+        !matches!(self, AuthMode::None)
+    }
+}
+
+/// An in-progress SASL exchange driven by `AUTHENTICATE` messages.
+#[derive(Debug)]
+pub struct SaslSession {
+    mechanism: SaslMechanism,
+    challenge: String,
+}
+
+#[derive(Debug)]
+enum SaslMechanism {
+    Plain { account: String, password: String },
+    External,
+    Scram(ScramClient),
+}
+
+impl SaslSession {
+    /// Picks a mechanism for `auth`, checking it against the mechanisms the
+    /// server advertised in `sasl=` (when it advertised any).
+    pub fn start(auth: &AuthMode, advertised: Option<&str>) -> anyhow::Result<Self> {
+        let mechanism = match auth {
+            AuthMode::None => bail!("no SASL credentials configured"),
+            AuthMode::SaslPlain { account, password } => SaslMechanism::Plain {
+                account: account.clone(),
+                password: password.clone(),
+            },
+            AuthMode::SaslExternal => SaslMechanism::External,
+            AuthMode::SaslScram { account, password } => {
+                let hash = ScramHash::select(advertised)?;
+                SaslMechanism::Scram(ScramClient::new(hash, account, password, &client_nonce()))
+            }
+        };
+        if let Some(advertised) = advertised.filter(|list| !list.is_empty()) {
+            let name = mechanism.name();
+            if !advertised
+                .split(',')
+                .any(|mech| mech.eq_ignore_ascii_case(name))
+            {
+                bail!("server does not offer SASL {name} (available: {advertised})");
+            }
+        }
+        Ok(Self {
+            mechanism,
+            challenge: String::new(),
+        })
+    }
+
+    /// The line that opens the exchange, e.g. `AUTHENTICATE PLAIN`.
+    pub fn initial_line(&self) -> String {
+        format!("AUTHENTICATE {}", self.mechanism.name())
+    }
+
+    /// Feeds one `AUTHENTICATE` argument from the server and returns the lines
+    /// to send back. Challenges split across several 400 byte chunks are
+    /// buffered until the final chunk arrives.
+    pub fn respond(&mut self, chunk: &str) -> anyhow::Result<Vec<String>> {
+        if chunk != "+" {
+            self.challenge.push_str(chunk);
+            if chunk.len() == CHUNK_SIZE {
+                return Ok(Vec::new());
+            }
+        }
+        let challenge = STANDARD
+            .decode(std::mem::take(&mut self.challenge))
+            .map_err(|err| anyhow!("invalid SASL challenge: {err}"))?;
+        let response = self.mechanism.step(&challenge)?;
+        Ok(authenticate_lines(&response))
+    }
+}
+
+impl SaslMechanism {
+    fn name(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain { .. } => "PLAIN",
+            SaslMechanism::External => "EXTERNAL",
+            SaslMechanism::Scram(client) => client.hash.mechanism(),
+        }
+    }
+
+    fn step(&mut self, challenge: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            SaslMechanism::Plain { account, password } => {
+                let mut payload = Vec::with_capacity(account.len() * 2 + password.len() + 2);
+                payload.extend_from_slice(account.as_bytes());
+                payload.push(0);
+                payload.extend_from_slice(account.as_bytes());
+                payload.push(0);
+                payload.extend_from_slice(password.as_bytes());
+                Ok(payload)
+            }
+            // An empty authorization identity: the server derives the account
+            // from the certificate fingerprint.
+            SaslMechanism::External => Ok(Vec::new()),
+            SaslMechanism::Scram(client) => client.step(challenge),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScramHash {
+    Sha256,
+    Sha1,
+}
+
+impl ScramHash {
+    /// Prefers SCRAM-SHA-256 and falls back to SCRAM-SHA-1 based on the
+    /// server's `sasl=` list. Servers that do not advertise a list get
+    /// SCRAM-SHA-256.
+    fn select(advertised: Option<&str>) -> anyhow::Result<Self> {
+        let Some(advertised) = advertised.filter(|list| !list.is_empty()) else {
+            return Ok(ScramHash::Sha256);
+        };
+        let offers = |name: &str| {
+            advertised
+                .split(',')
+                .any(|mech| mech.eq_ignore_ascii_case(name))
+        };
+        if offers(ScramHash::Sha256.mechanism()) {
+            Ok(ScramHash::Sha256)
+        } else if offers(ScramHash::Sha1.mechanism()) {
+            Ok(ScramHash::Sha1)
+        } else {
+            bail!("server does not offer SCRAM-SHA-256 or SCRAM-SHA-1 (available: {advertised})")
+        }
+    }
+
+    fn mechanism(self) -> &'static str {
+        match self {
+            ScramHash::Sha256 => "SCRAM-SHA-256",
+            ScramHash::Sha1 => "SCRAM-SHA-1",
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha256 => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha1 => {
+                let mut mac =
+                    Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn salted_password(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            ScramHash::Sha256 => {
+                let mut out = vec![0; 32];
+                pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut out);
+                out
+            }
+            ScramHash::Sha1 => {
+                let mut out = vec![0; 20];
+                pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, &mut out);
+                out
+            }
+        }
+    }
+}
+
+/// Client side of a SCRAM exchange (RFC 5802 / RFC 7677) without channel
+/// binding.
+#[derive(Debug)]
+struct ScramClient {
+    hash: ScramHash,
+    password: String,
+    client_first_bare: String,
+    nonce: String,
+    state: ScramState,
+}
+
+#[derive(Debug)]
+enum ScramState {
+    Initial,
+    AwaitServerFirst,
+    AwaitServerFinal { server_signature: Vec<u8> },
+    Done,
+}
+
+impl ScramClient {
+    fn new(hash: ScramHash, account: &str, password: &str, nonce: &str) -> Self {
+        let username = account.replace('=', "=3D").replace(',', "=2C");
+        Self {
+            hash,
+            password: password.to_string(),
+            client_first_bare: format!("n={username},r={nonce}"),
+            nonce: nonce.to_string(),
+            state: ScramState::Initial,
+        }
+    }
+
+    fn step(&mut self, challenge: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match std::mem::replace(&mut self.state, ScramState::Done) {
+            ScramState::Initial => {
+                self.state = ScramState::AwaitServerFirst;
+                Ok(format!("n,,{}", self.client_first_bare).into_bytes())
+            }
+            ScramState::AwaitServerFirst => {
+                let server_first =
+                    std::str::from_utf8(challenge).context("SCRAM server-first is not UTF-8")?;
+                let (response, server_signature) = self.client_final(server_first)?;
+                self.state = ScramState::AwaitServerFinal { server_signature };
+                Ok(response.into_bytes())
+            }
+            ScramState::AwaitServerFinal { server_signature } => {
+                let server_final =
+                    std::str::from_utf8(challenge).context("SCRAM server-final is not UTF-8")?;
+                if let Some(error) = scram_attr(server_final, 'e') {
+                    bail!("server rejected SCRAM authentication: {error}");
+                }
+                let verifier = scram_attr(server_final, 'v')
+                    .ok_or_else(|| anyhow!("SCRAM server-final has no verifier"))?;
+                let verifier = STANDARD
+                    .decode(verifier)
+                    .context("SCRAM server verifier is not base64")?;
+                if verifier != server_signature {
+                    bail!("SCRAM server signature mismatch");
+                }
+                Ok(Vec::new())
+            }
+            ScramState::Done => bail!("unexpected SCRAM challenge after completion"),
+        }
+    }
+
+    /// Builds `client-final-message` for `server_first` and returns it with
+    /// the server signature expected in `server-final-message`.
+    fn client_final(&self, server_first: &str) -> anyhow::Result<(String, Vec<u8>)> {
+        let nonce = scram_attr(server_first, 'r')
+            .ok_or_else(|| anyhow!("SCRAM server-first has no nonce"))?;
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            bail!("SCRAM server nonce does not extend the client nonce");
+        }
+        let salt = scram_attr(server_first, 's')
+            .ok_or_else(|| anyhow!("SCRAM server-first has no salt"))?;
+        let salt = STANDARD.decode(salt).context("SCRAM salt is not base64")?;
+        let iterations = scram_attr(server_first, 'i')
+            .and_then(|i| i.parse::<u32>().ok())
+            .filter(|i| (1..=MAX_SCRAM_ITERATIONS).contains(i))
+            .ok_or_else(|| anyhow!("SCRAM server-first has an invalid iteration count"))?;
+
+        let hash = self.hash;
+        let salted = hash.salted_password(self.password.as_bytes(), &salt, iterations);
+        let client_key = hash.hmac(&salted, b"Client Key");
+        let stored_key = hash.hash(&client_key);
+        // "biws" is base64("n,,"): no channel binding, no authzid.
+        let without_proof = format!("c=biws,r={nonce}");
+        let auth_message = format!("{},{server_first},{without_proof}", self.client_first_bare);
+        let client_signature = hash.hmac(&stored_key, auth_message.as_bytes());
+        let proof = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(key, sig)| key ^ sig)
+            .collect::<Vec<_>>();
+        let server_key = hash.hmac(&salted, b"Server Key");
+        let server_signature = hash.hmac(&server_key, auth_message.as_bytes());
+        let response = format!("{without_proof},p={}", STANDARD.encode(proof));
+        Ok((response, server_signature))
+    }
+}
+
+fn scram_attr(message: &str, name: char) -> Option<&str> {
+    message.split(',').find_map(|attr| {
+        let mut chars = attr.chars();
+        (chars.next() == Some(name) && chars.next() == Some('=')).then(|| &attr[2..])
+    })
+}
+
+fn client_nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+/// Encodes `payload` as one or more `AUTHENTICATE` lines. A payload whose
+/// encoded length is a multiple of 400 (including an empty one) is terminated
+/// with `AUTHENTICATE +`.
+fn authenticate_lines(payload: &[u8]) -> Vec<String> {
+    let encoded = STANDARD.encode(payload);
+    let mut lines = encoded
+        .as_bytes()
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| format!("AUTHENTICATE {}", String::from_utf8_lossy(chunk)))
+        .collect::<Vec<_>>();
+    if encoded.len().is_multiple_of(CHUNK_SIZE) {
+        lines.push("AUTHENTICATE +".to_string());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scram_session(hash: ScramHash, nonce: &str) -> SaslSession {
+        SaslSession {
+            mechanism: SaslMechanism::Scram(ScramClient::new(hash, "user", "pencil", nonce)),
+            challenge: String::new(),
+        }
+    }
+
+    /// Plays the server side of an exchange: each server message is sent as
+    /// an `AUTHENTICATE` line and the decoded client replies are returned.
+    fn run_exchange(session: &mut SaslSession, server_messages: &[&str]) -> Vec<String> {
+        let mut replies = Vec::new();
+        for message in server_messages {
+            let chunk = if message.is_empty() {
+                "+".to_string()
+            } else {
+                STANDARD.encode(message)
+            };
+            let lines = session
+                .respond(&chunk)
+                .expect("client accepts server message");
+            let encoded = lines
+                .iter()
+                .map(|line| line.trim_start_matches("AUTHENTICATE "))
+                .filter(|chunk| *chunk != "+")
+                .collect::<String>();
+            let decoded = STANDARD.decode(encoded).expect("client sends base64");
+            replies.push(String::from_utf8(decoded).expect("client sends UTF-8"));
+        }
+        replies
+    }
+
+    #[test]
+    fn scram_sha256_matches_rfc7677() {
+        let mut session = scram_session(ScramHash::Sha256, "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(session.initial_line(), "AUTHENTICATE SCRAM-SHA-256");
+        let replies = run_exchange(
+            &mut session,
+            &[
+                "",
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+            ],
+        );
+        assert_eq!(
+            replies,
+            [
+                "n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+                "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn scram_sha1_matches_rfc5802() {
+        let mut session = scram_session(ScramHash::Sha1, "fyko+d2lbbFgONRv9qkxdawL");
+        let replies = run_exchange(
+            &mut session,
+            &[
+                "",
+                "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+                "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=",
+            ],
+        );
+        assert_eq!(
+            replies[1],
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts="
+        );
+    }
+
+    #[test]
+    fn scram_rejects_bad_server_signature() {
+        let mut session = scram_session(ScramHash::Sha256, "rOprNGfwEbeRWgbNEkqO");
+        run_exchange(
+            &mut session,
+            &[
+                "",
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            ],
+        );
+        let forged = STANDARD.encode("v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        assert!(session.respond(&forged).is_err());
+    }
+
+    #[test]
+    fn scram_rejects_foreign_nonce() {
+        let mut session = scram_session(ScramHash::Sha256, "clientnonce");
+        run_exchange(&mut session, &[""]);
+        let server_first = STANDARD.encode("r=othernonce123,s=QSXCR+Q6sek8bf92,i=4096");
+        assert!(session.respond(&server_first).is_err());
+    }
+
+    #[test]
+    fn scram_mechanism_follows_advertised_list() {
+        assert_eq!(ScramHash::select(None).unwrap(), ScramHash::Sha256);
+        assert_eq!(
+            ScramHash::select(Some("PLAIN,SCRAM-SHA-1,SCRAM-SHA-256")).unwrap(),
+            ScramHash::Sha256
+        );
+        assert_eq!(
+            ScramHash::select(Some("PLAIN,SCRAM-SHA-1")).unwrap(),
+            ScramHash::Sha1
+        );
+        assert!(ScramHash::select(Some("PLAIN,EXTERNAL")).is_err());
+    }
+
+    #[test]
+    fn long_payloads_are_chunked() {
+        let lines = authenticate_lines(&[b'x'; 300]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), "AUTHENTICATE ".len() + CHUNK_SIZE);
+        assert_eq!(lines[1], "AUTHENTICATE +");
+        assert_eq!(authenticate_lines(&[]), ["AUTHENTICATE +"]);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{collections::VecDeque, io, time::Duration};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    time::Instant,
+};
+
+use crate::encoding::Encoding;
+
+/// Outgoing lines paced by a token bucket, so a paste or a long auto-join
+/// list doesn't get us disconnected for excess flood.
+pub struct SendQueue<W> {
+    writer: BufWriter<W>,
+    encoding: Encoding,
+    queue: VecDeque<String>,
+    bucket: TokenBucket,
+    /// Off until registration completes: CAP, SASL and NICK/USER must not
+    /// wait behind the bucket or the server may time us out.
+    paced: bool,
+}
+
+impl<W: AsyncWrite + Unpin> SendQueue<W> {
+    /// Writes lines in `encoding`, allowing `burst` at once and then one
+    /// more every `refill`.
+    pub fn new(writer: W, encoding: Encoding, burst: u32, refill: Duration) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            encoding,
+            queue: VecDeque::new(),
+            bucket: TokenBucket::new(burst, refill, Instant::now()),
+            paced: false,
+        }
+    }
+
+    /// Starts pacing `send`; until then lines go out as soon as they are sent.
+    pub fn start_pacing(&mut self) {
+        self.paced = true;
+    }
+
+    /// Queues `line` behind anything already waiting and writes what the
+    /// bucket allows right away.
+    pub async fn send(&mut self, line: &str) -> io::Result<()> {
+        if !self.paced {
+            self.write(line).await?;
+            return self.writer.flush().await;
+        }
+        self.queue.push_back(line.to_string());
+        self.flush_ready().await
+    }
+
+    /// Writes `line` immediately, ahead of the queue. For PONG and QUIT,
+    /// which must not wait behind a paste.
+    pub async fn send_now(&mut self, line: &str) -> io::Result<()> {
+        self.bucket.try_take(Instant::now());
+        self.write(line).await?;
+        self.writer.flush().await
+    }
+
+    /// Writes queued lines for as long as there are tokens.
+    pub async fn flush_ready(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut wrote = false;
+        while !self.queue.is_empty() && self.bucket.try_take(now) {
+            if let Some(line) = self.queue.pop_front() {
+                self.write(&line).await?;
+                wrote = true;
+            }
+        }
+        if wrote {
+            self.writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Lines still waiting for a token.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// When the next queued line can go out, if any are waiting.
+    pub fn next_send(&self) -> Option<std::time::Instant> {
+        (!self.queue.is_empty()).then(|| self.bucket.next_token().into_std())
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
+
+    async fn write(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(&self.encoding.encode(line)).await?;
+        self.writer.write_all(b"\r\n").await
+    }
+}
+
+struct TokenBucket {
+    capacity: u32,
+    tokens: u32,
+    refill: Duration,
+    /// When `tokens` was last topped up; the next token is due `refill` later.
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, refill: Duration, now: Instant) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            tokens: capacity,
+            refill,
+            updated: now,
+        }
+    }
+
+    fn top_up(&mut self, now: Instant) {
+        if self.tokens >= self.capacity {
+            self.updated = now;
+            return;
+        }
+        if self.refill.is_zero() {
+            self.tokens = self.capacity;
+            self.updated = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.updated);
+        let earned = (elapsed.as_millis() / self.refill.as_millis().max(1)) as u32;
+        if earned > 0 {
+            self.tokens = self.tokens.saturating_add(earned).min(self.capacity);
+            self.updated += self.refill * earned;
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.top_up(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn next_token(&self) -> Instant {
+        if self.tokens > 0 {
+            self.updated
+        } else {
+            self.updated + self.refill
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    fn queue(burst: u32, refill_secs: u64) -> SendQueue<Vec<u8>> {
+        SendQueue::new(
+            Vec::new(),
+            Encoding::Utf8,
+            burst,
+            Duration::from_secs(refill_secs),
+        )
+    }
+
+    fn sent(queue: &SendQueue<Vec<u8>>) -> Vec<&str> {
+        std::str::from_utf8(queue.writer.get_ref())
+            .unwrap()
+            .split_terminator("\r\n")
+            .collect()
+    }
+
+    async fn send_all(queue: &mut SendQueue<Vec<u8>>, lines: &[&str]) {
+        for line in lines {
+            queue.send(line).await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_goes_out_then_one_per_refill() {
+        let mut queue = queue(3, 2);
+        queue.start_pacing();
+        send_all(&mut queue, &["1", "2", "3", "4", "5"]).await;
+        assert_eq!(sent(&queue), ["1", "2", "3"]);
+        assert_eq!(queue.pending(), 2);
+        let next = queue.next_send().unwrap();
+        assert_eq!(next, (Instant::now() + Duration::from_secs(2)).into_std());
+
+        advance(Duration::from_secs(1)).await;
+        queue.flush_ready().await.unwrap();
+        assert_eq!(queue.pending(), 2);
+        advance(Duration::from_secs(1)).await;
+        queue.flush_ready().await.unwrap();
+        assert_eq!(sent(&queue), ["1", "2", "3", "4"]);
+        advance(Duration::from_secs(2)).await;
+        queue.flush_ready().await.unwrap();
+        assert_eq!(sent(&queue), ["1", "2", "3", "4", "5"]);
+        assert_eq!(queue.next_send(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_time_refills_no_more_than_the_burst() {
+        let mut queue = queue(2, 1);
+        queue.start_pacing();
+        send_all(&mut queue, &["1", "2"]).await;
+        advance(Duration::from_secs(60)).await;
+        send_all(&mut queue, &["3", "4", "5", "6"]).await;
+        assert_eq!(sent(&queue), ["1", "2", "3", "4"]);
+        assert_eq!(queue.pending(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_keeps_the_remainder_of_a_partial_interval() {
+        let mut queue = queue(1, 2);
+        queue.start_pacing();
+        send_all(&mut queue, &["1", "2", "3"]).await;
+        advance(Duration::from_secs(3)).await;
+        queue.flush_ready().await.unwrap();
+        assert_eq!(sent(&queue), ["1", "2"]);
+        // Half of the next interval already passed above.
+        advance(Duration::from_secs(1)).await;
+        queue.flush_ready().await.unwrap();
+        assert_eq!(sent(&queue), ["1", "2", "3"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lines_before_registration_skip_the_bucket() {
+        let mut queue = queue(1, 2);
+        send_all(
+            &mut queue,
+            &["CAP LS 302", "NICK a", "USER a 0 * :a", "CAP END"],
+        )
+        .await;
+        assert_eq!(queue.pending(), 0);
+        assert_eq!(sent(&queue).len(), 4);
+        // Registration didn't spend the burst.
+        queue.start_pacing();
+        send_all(&mut queue, &["JOIN #a", "JOIN #b"]).await;
+        assert_eq!(sent(&queue)[4..], ["JOIN #a"]);
+        assert_eq!(queue.pending(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_now_goes_ahead_of_the_queue() {
+        let mut queue = queue(1, 2);
+        queue.start_pacing();
+        send_all(&mut queue, &["PRIVMSG #a :1", "PRIVMSG #a :2"]).await;
+        queue.send_now("PONG :x").await.unwrap();
+        assert_eq!(sent(&queue), ["PRIVMSG #a :1", "PONG :x"]);
+        assert_eq!(queue.pending(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_refill_never_paces() {
+        let mut queue = queue(1, 0);
+        queue.start_pacing();
+        send_all(&mut queue, &["1", "2", "3"]).await;
+        assert_eq!(sent(&queue), ["1", "2", "3"]);
+    }
+}
@@ -1,13 +1,26 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::Context;
-use tokio::{fs, io::AsyncWriteExt};
+use parking_lot::Mutex;
+use tokio::{fs, io::AsyncWriteExt, sync::OwnedMutexGuard};
 
-use crate::messages::ChatMessage;
+use crate::{isupport::CaseMapping, messages::ChatMessage};
 
 #[derive(Clone)]
 pub struct ScrollbackStore {
     base_dir: Arc<PathBuf>,
+    /// Unfolded paths whose legacy log has already been dealt with, so
+    /// `resolve_path` only touches the filesystem once per spelling.
+    resolved: Arc<Mutex<HashSet<PathBuf>>>,
+    /// One lock per log, keyed by its folded path, held while the log is
+    /// migrated, appended to or read so those never interleave.
+    locks: Arc<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Drop writes and read nothing back; see `discard`.
+    discard: bool,
 }
 
 impl ScrollbackStore {
@@ -20,9 +33,24 @@ impl ScrollbackStore {
         })?;
         Ok(Self {
             base_dir: Arc::new(base_dir),
+            resolved: Arc::new(Mutex::new(HashSet::new())),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            discard: false,
         })
     }
 
+    /// A store that keeps nothing, for tests and the fuzz targets, which
+    /// shouldn't touch the disk.
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn discard() -> Self {
+        Self {
+            base_dir: Arc::new(PathBuf::new()),
+            resolved: Arc::default(),
+            locks: Arc::default(),
+            discard: true,
+        }
+    }
+
     fn target_path(&self, storage_key: &str, target: &str) -> PathBuf {
         let target = sanitize_component(target);
         let mut path = self.base_dir.as_ref().clone();
@@ -31,8 +59,50 @@ impl ScrollbackStore {
         path
     }
 
-    pub async fn append(&self, storage_key: &str, message: &ChatMessage) -> anyhow::Result<()> {
-        let path = self.target_path(storage_key, &message.target);
+    /// The scrollback file for `target`, keyed by its casefolded name so that
+    /// `#Rust` and `#rust` share one log. A log written before targets were
+    /// folded is moved, or merged if both exist, the first time it is touched.
+    /// The returned guard keeps other tasks off the log until it is dropped.
+    async fn lock_log(
+        &self,
+        storage_key: &str,
+        casemapping: CaseMapping,
+        target: &str,
+    ) -> (PathBuf, OwnedMutexGuard<()>) {
+        let path = self.target_path(storage_key, &casemapping.fold(target));
+        let lock = self.locks.lock().entry(path.clone()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        let legacy = self.target_path(storage_key, target);
+        if legacy == path || self.resolved.lock().contains(&legacy) {
+            return (path, guard);
+        }
+        if fs::try_exists(&legacy).await.unwrap_or(false) {
+            let migrated = if fs::try_exists(&path).await.unwrap_or(true) {
+                merge_logs(&legacy, &path).await
+            } else {
+                fs::rename(&legacy, &path).await.map_err(Into::into)
+            };
+            if let Err(err) = migrated {
+                tracing::warn!("failed to migrate scrollback {}: {err}", legacy.display());
+                return (legacy, guard);
+            }
+        }
+        self.resolved.lock().insert(legacy);
+        (path, guard)
+    }
+
+    pub async fn append(
+        &self,
+        storage_key: &str,
+        casemapping: CaseMapping,
+        message: &ChatMessage,
+    ) -> anyhow::Result<()> {
+        if self.discard {
+            return Ok(());
+        }
+        let (path, _guard) = self
+            .lock_log(storage_key, casemapping, &message.target)
+            .await;
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent).await.with_context(|| {
                 format!("failed to create parent directories for {}", path.display())
@@ -56,10 +126,14 @@ impl ScrollbackStore {
     pub async fn read_last(
         &self,
         storage_key: &str,
+        casemapping: CaseMapping,
         target: &str,
         limit: Option<usize>,
     ) -> anyhow::Result<Vec<ChatMessage>> {
-        let path = self.target_path(storage_key, target);
+        if self.discard {
+            return Ok(Vec::new());
+        }
+        let (path, _guard) = self.lock_log(storage_key, casemapping, target).await;
         let data = match fs::read_to_string(&path).await {
             Ok(data) => data,
             Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
@@ -89,6 +163,47 @@ impl ScrollbackStore {
     }
 }
 
+/// Merges the legacy log at `from` into `into` by timestamp, then removes it.
+/// Both logs are already in order, so a single merge pass keeps them so.
+async fn merge_logs(from: &Path, into: &Path) -> anyhow::Result<()> {
+    let legacy = fs::read_to_string(from).await?;
+    let current = fs::read_to_string(into).await?;
+    let mut merged = String::with_capacity(legacy.len() + current.len());
+    let mut legacy = timestamped_lines(&legacy).peekable();
+    let mut current = timestamped_lines(&current).peekable();
+    loop {
+        let next = match (legacy.peek(), current.peek()) {
+            (Some((a, _)), Some((b, _))) if a <= b => legacy.next(),
+            (Some(_), None) => legacy.next(),
+            _ => current.next(),
+        };
+        let Some((_, line)) = next else {
+            break;
+        };
+        merged.push_str(line);
+        merged.push('\n');
+    }
+    let tmp = into.with_extension("jsonl.tmp");
+    fs::write(&tmp, merged).await?;
+    fs::rename(&tmp, into).await?;
+    fs::remove_file(from).await?;
+    Ok(())
+}
+
+/// Non-empty log lines with their timestamp. Lines that don't parse take the
+/// timestamp of the line before them, so they stay where they were.
+fn timestamped_lines(data: &str) -> impl Iterator<Item = (i64, &str)> {
+    let mut last = i64::MIN;
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(move |line| {
+            if let Ok(msg) = serde_json::from_str::<ChatMessage>(line) {
+                last = msg.timestamp;
+            }
+            (last, line)
+        })
+}
+
 fn sanitize_component(input: &str) -> String {
     let mut s = String::with_capacity(input.len());
     for c in input.chars() {
@@ -102,3 +217,77 @@ fn sanitize_component(input: &str) -> String {
     }
     s
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::MessageKind;
+
+    fn message(target: &str, timestamp: i64) -> ChatMessage {
+        ChatMessage {
+            connection_id: "test".into(),
+            target: target.into(),
+            sender: None,
+            message: format!("message {timestamp}"),
+            kind: MessageKind::Privmsg,
+            timestamp,
+            metadata: None,
+        }
+    }
+
+    fn write_log(path: &Path, timestamps: &[i64]) {
+        let data: String = timestamps
+            .iter()
+            .map(|timestamp| serde_json::to_string(&message("#Rust", *timestamp)).unwrap() + "\n")
+            .collect();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn migrates_legacy_log_once_under_concurrent_use() {
+        let dir = std::env::temp_dir().join(format!("fluxchat-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = ScrollbackStore::new(dir.clone()).unwrap();
+        let legacy = store.target_path("net", "#Rust");
+        let folded = store.target_path("net", "#rust");
+        std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        write_log(&legacy, &[1, 3, 5]);
+        write_log(&folded, &[2, 4]);
+
+        let mut tasks = Vec::new();
+        for timestamp in 10..30 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                let target = if timestamp % 2 == 0 { "#Rust" } else { "#rust" };
+                store
+                    .append("net", CaseMapping::Ascii, &message(target, timestamp))
+                    .await
+                    .unwrap();
+                store
+                    .read_last("net", CaseMapping::Ascii, "#RUST", None)
+                    .await
+                    .unwrap();
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let messages = store
+            .read_last("net", CaseMapping::Ascii, "#rust", None)
+            .await
+            .unwrap();
+        let mut timestamps: Vec<i64> = messages.iter().map(|msg| msg.timestamp).collect();
+        assert_eq!(&timestamps[..5], [1, 2, 3, 4, 5]);
+        timestamps.sort();
+        assert_eq!(
+            timestamps,
+            [1, 2, 3, 4, 5]
+                .into_iter()
+                .chain(10..30)
+                .collect::<Vec<_>>()
+        );
+        assert!(!legacy.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -35,13 +35,72 @@ type IrcEvent =
       channel: string;
       topic: string;
       setter?: string | null;
+      set_at?: number | null;
     }
   | {
       type: "error";
       connection_id: string;
       message: string;
+    }
+  | {
+      type: "capabilities";
+      connection_id: string;
+      enabled: string[];
+    }
+  | {
+      type: "reconnecting";
+      connection_id: string;
+      attempt: number;
+      delay_ms: number;
+    }
+  | {
+      type: "reconnected";
+      connection_id: string;
+      attempt: number;
+    }
+  | {
+      type: "lag";
+      connection_id: string;
+      lag_ms: number;
+    }
+  | {
+      type: "send_queue";
+      connection_id: string;
+      pending: number;
+    }
+  | {
+      type: "channel_member";
+      connection_id: string;
+      channel: string;
+      user: ChannelUserPayload;
+    }
+  | {
+      type: "channel_modes";
+      connection_id: string;
+      channel: string;
+      modes: Record<string, string | null>;
+    }
+  | {
+      type: "nick";
+      connection_id: string;
+      old_nick: string;
+      new_nick: string;
+      own: boolean;
+    }
+  | {
+      type: "server_info";
+      connection_id: string;
+      info: ServerInfoPayload;
     };
 
+type CaseMapping = "ascii" | "rfc1459" | "strict-rfc1459";
+
+interface ServerInfoPayload {
+  network?: string | null;
+  casemapping: CaseMapping;
+  chantypes: string;
+}
+
 type BufferKind = "status" | "channel" | "query";
 
 interface BufferState {
@@ -50,6 +109,9 @@ interface BufferState {
   messages: ChatMessage[];
   users: ChannelUser[];
   topic?: string;
+  topicSetter?: string;
+  topicTime?: number;
+  modes?: Record<string, string | null>;
   unread: boolean;
   loaded: boolean;
   loading: boolean;
@@ -60,6 +122,12 @@ interface ConnectionState {
   server: string;
   nickname: string;
   connected: boolean;
+  capabilities: string[];
+  lagMs?: number;
+  /** Lines held back by flood control. */
+  pendingLines?: number;
+  casemapping: CaseMapping;
+  /** Keyed by `bufferKey`, so differently-cased names share one buffer. */
   buffers: Record<string, BufferState>;
 }
 
@@ -78,8 +146,28 @@ interface ConnectFormState {
   realname: string;
   password: string;
   autoJoin: string;
+  saslMechanism: SaslMechanism;
+  saslAccount: string;
+  saslPassword: string;
+  saslRequired: boolean;
+  clientCert: string;
+  clientKey: string;
+  clientCertPassword: string;
 }
 
+type SaslMechanism = "none" | "plain" | "scram" | "external";
+
+type AuthMode =
+  | { mode: "none" }
+  | { mode: "saslPlain"; account: string; password: string }
+  | { mode: "saslScram"; account: string; password: string }
+  | { mode: "saslExternal" };
+
+type ClientCertificate =
+  | { format: "none" }
+  | { format: "pkcs12"; path: string; password?: string | null }
+  | { format: "pem"; certPath: string; keyPath: string };
+
 interface SavedConnection {
   server: string;
   port: number;
@@ -89,6 +177,9 @@ interface SavedConnection {
   realname?: string | null;
   password?: string | null;
   autoJoin: string[];
+  auth?: AuthMode;
+  saslRequired?: boolean;
+  clientCert?: ClientCertificate;
 }
 
 interface ChannelUserPayload {
@@ -98,6 +189,27 @@ interface ChannelUserPayload {
 
 type ChannelUser = ChannelUserPayload;
 
+interface ChannelSnapshot {
+  name: string;
+  topic?: string | null;
+  topicSetter?: string | null;
+  topicTime?: number | null;
+  created?: number | null;
+  modes: Record<string, string | null>;
+  members: ChannelUserPayload[];
+}
+
+/** What `irc_connection_state` returns for a live connection. */
+interface ConnectionStatePayload {
+  connectionId: string;
+  server: string;
+  nickname: string;
+  connected: boolean;
+  capabilities: string[];
+  serverInfo: ServerInfoPayload;
+  channels: ChannelSnapshot[];
+}
+
 interface ConnectionRemap {
   from: string | null;
   to: string | null;
@@ -126,12 +238,99 @@ const DEFAULT_CONNECT_FORM: ConnectFormState = {
   realname: "Flux User",
   password: "",
   autoJoin: "#fluxchat",
+  saslMechanism: "none",
+  saslAccount: "",
+  saslPassword: "",
+  saslRequired: false,
+  clientCert: "",
+  clientKey: "",
+  clientCertPassword: "",
 };
 
+function savedToForm(saved: SavedConnection): ConnectFormState {
+  const auth =
+    saved.auth?.mode === "saslPlain" || saved.auth?.mode === "saslScram"
+      ? saved.auth
+      : undefined;
+  const cert = saved.clientCert;
+  let saslMechanism: SaslMechanism = "none";
+  if (saved.auth?.mode === "saslPlain") {
+    saslMechanism = "plain";
+  } else if (saved.auth?.mode === "saslScram") {
+    saslMechanism = "scram";
+  } else if (saved.auth?.mode === "saslExternal") {
+    saslMechanism = "external";
+  }
+  return {
+    server: saved.server,
+    port: String(saved.port),
+    useTls: saved.useTls,
+    nickname: saved.nickname,
+    username: saved.username ?? "",
+    realname: saved.realname ?? "",
+    password: saved.password ?? "",
+    autoJoin: saved.autoJoin.join(", "),
+    saslMechanism,
+    saslAccount: auth?.account ?? "",
+    saslPassword: auth?.password ?? "",
+    saslRequired: saved.saslRequired ?? false,
+    clientCert:
+      cert?.format === "pkcs12" ? cert.path : cert?.format === "pem" ? cert.certPath : "",
+    clientKey: cert?.format === "pem" ? cert.keyPath : "",
+    clientCertPassword: cert?.format === "pkcs12" ? (cert.password ?? "") : "",
+  };
+}
+
+function formAuthMode(form: ConnectFormState): AuthMode {
+  switch (form.saslMechanism) {
+    case "plain":
+      return {
+        mode: "saslPlain",
+        account: form.saslAccount.trim(),
+        password: form.saslPassword,
+      };
+    case "scram":
+      return {
+        mode: "saslScram",
+        account: form.saslAccount.trim(),
+        password: form.saslPassword,
+      };
+    case "external":
+      return { mode: "saslExternal" };
+    default:
+      return { mode: "none" };
+  }
+}
+
+function formClientCert(form: ConnectFormState): ClientCertificate {
+  const certPath = form.clientCert.trim();
+  if (!certPath) {
+    return { format: "none" };
+  }
+  const keyPath = form.clientKey.trim();
+  if (keyPath) {
+    return { format: "pem", certPath, keyPath };
+  }
+  return { format: "pkcs12", path: certPath, password: form.clientCertPassword || null };
+}
+
 function randomQuitReason(): string {
   return QUIT_REASONS[Math.floor(Math.random() * QUIT_REASONS.length)] ?? "lost connection";
 }
 
+/** Commands that send to the server reject with `{ kind, message }`. */
+interface CommandError {
+  kind: string;
+  message: string;
+}
+
+function errorMessage(error: unknown): string {
+  if (typeof error === "object" && error !== null && "message" in error) {
+    return (error as CommandError).message;
+  }
+  return String(error);
+}
+
 function App() {
   const [connections, setConnections] = useState<ConnectionsState>({});
   const [active, setActive] = useState<ActiveSelection>(null);
@@ -144,6 +343,7 @@ function App() {
   const [status, setStatus] = useState<string | null>(null);
   const [savedConnections, setSavedConnections] = useState<SavedConnection[]>([]);
   const activeRef = useRef<ActiveSnapshot>({ connectionId: null, buffer: null });
+  const connectionsRef = useRef<ConnectionsState>({});
   const savedDefaultsLoaded = useRef(false);
   const messagesEndRef = useRef<HTMLDivElement | null>(null);
   const messageInputRef = useRef<HTMLInputElement | null>(null);
@@ -158,22 +358,17 @@ function App() {
       : { connectionId: null, buffer: null };
   }, [active]);
 
+  useEffect(() => {
+    connectionsRef.current = connections;
+  }, [connections]);
+
   const refreshSavedConnections = useCallback(() => {
     invoke<SavedConnection[]>("irc_saved_connections")
       .then((list) => {
         setSavedConnections(list);
         if (!savedDefaultsLoaded.current && list.length > 0) {
           const last = list[list.length - 1];
-          setConnectForm({
-            server: last.server,
-            port: String(last.port),
-            useTls: last.useTls,
-            nickname: last.nickname,
-            username: last.username ?? "",
-            realname: last.realname ?? "",
-            password: last.password ?? "",
-            autoJoin: last.autoJoin.join(", "),
-          });
+          setConnectForm(savedToForm(last));
           savedDefaultsLoaded.current = true;
         }
       })
@@ -187,6 +382,34 @@ function App() {
     refreshSavedConnections();
   }, [refreshSavedConnections]);
 
+  // Connections outlive the webview, so pick up any that are already running.
+  useEffect(() => {
+    invoke<string[]>("irc_list_connections")
+      .then((ids) =>
+        Promise.all(
+          ids.map((connectionId) =>
+            invoke<ConnectionStatePayload>("irc_connection_state", {
+              args: { connectionId },
+            }),
+          ),
+        ),
+      )
+      .then((states) => {
+        if (states.length === 0) {
+          return;
+        }
+        setConnections((prev) => states.reduce(hydrateConnection, prev));
+        setActive(
+          (current) => current ?? { connectionId: states[0].connectionId, buffer: "*server" },
+        );
+        setShowConnectForm(false);
+      })
+      .catch((error) => {
+        console.error("Failed to restore connections", error);
+        setStatus(`Failed to restore connections: ${String(error)}`);
+      });
+  }, []);
+
   const focusMessageInput = useCallback(() => {
     requestAnimationFrame(() => {
       messageInputRef.current?.focus();
@@ -284,6 +507,46 @@ function App() {
             };
           });
         });
+
+      if (deriveBufferKind(bufferName) === "channel") {
+        invoke<ChannelSnapshot | null>("irc_channel_state", {
+          args: {
+            connectionId,
+            channel: bufferName,
+          },
+        })
+          .then((snapshot) => {
+            if (!snapshot) {
+              return;
+            }
+            setConnections((prev) => {
+              const connection = prev[connectionId];
+              const existing = connection?.buffers[bufferName];
+              if (!connection || !existing) {
+                return prev;
+              }
+              return {
+                ...prev,
+                [connectionId]: {
+                  ...connection,
+                  buffers: {
+                    ...connection.buffers,
+                    [bufferName]: {
+                      ...existing,
+                      topic: snapshot.topic ?? existing.topic,
+                      topicSetter: snapshot.topicSetter ?? existing.topicSetter,
+                      topicTime: snapshot.topicTime ?? existing.topicTime,
+                      users: normalizeUsers(snapshot.members),
+                    },
+                  },
+                },
+              };
+            });
+          })
+          .catch((error) => {
+            console.error(error);
+          });
+      }
     },
     [],
   );
@@ -321,6 +584,28 @@ function App() {
             : "Disconnected",
         );
         break;
+      case "reconnecting":
+        setStatus(`Reconnecting (attempt ${payload.attempt})...`);
+        break;
+      case "nick":
+        setActive((current) => {
+          if (!current || current.connectionId !== payload.connection_id) {
+            return current;
+          }
+          const connection = connectionsRef.current[payload.connection_id];
+          if (!connection || current.buffer !== bufferKey(connection, payload.old_nick)) {
+            return current;
+          }
+          return { ...current, buffer: bufferKey(connection, payload.new_nick) };
+        });
+        break;
+      case "server_info":
+        setActive((current) =>
+          current && current.connectionId === payload.connection_id
+            ? { ...current, buffer: foldCase(payload.info.casemapping, current.buffer) }
+            : current,
+        );
+        break;
       default:
         break;
     }
@@ -422,16 +707,7 @@ function App() {
 
   const handleApplySaved = useCallback(
     (saved: SavedConnection) => {
-      setConnectForm({
-        server: saved.server,
-        port: String(saved.port),
-        useTls: saved.useTls,
-        nickname: saved.nickname,
-        username: saved.username ?? "",
-        realname: saved.realname ?? "",
-        password: saved.password ?? "",
-        autoJoin: saved.autoJoin.join(", "),
-      });
+      setConnectForm(savedToForm(saved));
       setShowConnectForm(true);
     },
     [],
@@ -461,7 +737,7 @@ function App() {
         });
       } catch (error) {
         console.error(error);
-        setStatus(`Failed to disconnect: ${String(error)}`);
+        setStatus(`Failed to disconnect: ${errorMessage(error)}`);
         setConnections((prev) => {
           const existing = prev[connectionId];
           if (!existing) {
@@ -485,12 +761,13 @@ function App() {
       if (!activeConnection || !nickname || !activeConnection.connected) {
         return;
       }
+      const key = bufferKey(activeConnection, nickname);
       setConnections((prev) => {
         const connection = prev[activeConnection.id];
         if (!connection) {
           return prev;
         }
-        if (connection.buffers[nickname]) {
+        if (connection.buffers[key]) {
           return prev;
         }
         return {
@@ -499,13 +776,13 @@ function App() {
             ...connection,
             buffers: {
               ...connection.buffers,
-              [nickname]: createBuffer(nickname, "query"),
+              [key]: createBuffer(nickname, "query"),
             },
           },
         };
       });
-      setActive({ connectionId: activeConnection.id, buffer: nickname });
-      loadBufferIfNeeded(activeConnection.id, nickname);
+      setActive({ connectionId: activeConnection.id, buffer: key });
+      loadBufferIfNeeded(activeConnection.id, key);
       focusMessageInput();
     },
     [activeConnection, focusMessageInput, loadBufferIfNeeded],
@@ -539,6 +816,9 @@ function App() {
             realname: connectForm.realname.trim() || undefined,
             password: connectForm.password || undefined,
             auto_join: autoJoin,
+            auth: formAuthMode(connectForm),
+            sasl_required: connectForm.saslRequired,
+            client_cert: formClientCert(connectForm),
           },
         });
         setStatus(`Connecting to ${connectForm.server.trim()}...`);
@@ -552,7 +832,7 @@ function App() {
         refreshSavedConnections();
       } catch (error) {
         console.error(error);
-        setStatus(`Connection failed: ${String(error)}`);
+        setStatus(`Connection failed: ${errorMessage(error)}`);
       } finally {
         setIsConnecting(false);
       }
@@ -585,7 +865,7 @@ function App() {
           });
         } catch (error) {
           console.error(error);
-          setStatus(`Failed to send message: ${String(error)}`);
+          setStatus(`Failed to send message: ${errorMessage(error)}`);
         }
       }
       setMessageInput("");
@@ -621,7 +901,7 @@ function App() {
       setIsEditingTopic(false);
     } catch (error) {
       console.error(error);
-      setStatus(`Failed to update topic: ${String(error)}`);
+      setStatus(`Failed to update topic: ${errorMessage(error)}`);
     }
   }, [activeBuffer, activeConnection, topicDraft]);
 
@@ -654,12 +934,13 @@ function App() {
         setJoinDrafts((prev) => ({ ...prev, [connectionId]: "" }));
         const inputEl = event.currentTarget.querySelector<HTMLInputElement>('input');
         inputEl?.blur();
-        setActive({ connectionId, buffer: channel });
-        loadBufferIfNeeded(connectionId, channel);
+        const key = bufferKey(connection, channel);
+        setActive({ connectionId, buffer: key });
+        loadBufferIfNeeded(connectionId, key);
         focusMessageInput();
       } catch (error) {
         console.error(error);
-        setStatus(`Failed to join ${channel}: ${String(error)}`);
+        setStatus(`Failed to join ${channel}: ${errorMessage(error)}`);
       }
     },
     [connections, joinDrafts, loadBufferIfNeeded, focusMessageInput],
@@ -676,12 +957,13 @@ function App() {
       if (!connection || !connection.connected) {
         return;
       }
+      const key = bufferKey(connection, nickname);
       setConnections((prev) => {
         const connection = prev[connectionId];
         if (!connection || !connection.connected) {
           return prev;
         }
-        if (connection.buffers[nickname]) {
+        if (connection.buffers[key]) {
           return prev;
         }
         return {
@@ -690,14 +972,14 @@ function App() {
             ...connection,
             buffers: {
               ...connection.buffers,
-              [nickname]: createBuffer(nickname, "query"),
+              [key]: createBuffer(nickname, "query"),
             },
           },
         };
       });
       setQueryDrafts((prev) => ({ ...prev, [connectionId]: "" }));
-      setActive({ connectionId, buffer: nickname });
-      loadBufferIfNeeded(connectionId, nickname);
+      setActive({ connectionId, buffer: key });
+      loadBufferIfNeeded(connectionId, key);
       const inputEl = event.currentTarget.querySelector<HTMLInputElement>('input');
       inputEl?.blur();
       focusMessageInput();
@@ -729,7 +1011,7 @@ function App() {
           });
         } catch (error) {
           console.error(error);
-          setStatus(`Failed to part ${buffer.name}: ${String(error)}`);
+          setStatus(`Failed to part ${buffer.name}: ${errorMessage(error)}`);
           return;
         }
       }
@@ -796,8 +1078,8 @@ function App() {
     });
 
     return buffers.map((buffer) => {
-      const isActive =
-        active?.connectionId === connection.id && active.buffer === buffer.name;
+      const key = bufferKey(connection, buffer.name);
+      const isActive = active?.connectionId === connection.id && active.buffer === key;
       const buttonClasses = ["buffer-pill"];
       if (isActive) {
         buttonClasses.push("active");
@@ -807,10 +1089,10 @@ function App() {
       }
       const closable = buffer.name !== "*server";
       return (
-        <div key={`${connection.id}-${buffer.name}`} className="buffer-row">
+        <div key={`${connection.id}-${key}`} className="buffer-row">
           <button
             className={buttonClasses.join(" ")}
-            onClick={() => handleSelectBuffer(connection.id, buffer.name)}
+            onClick={() => handleSelectBuffer(connection.id, key)}
           >
             <span className="buffer-name">{bufferLabel(buffer)}</span>
             {buffer.unread && !isActive ? <span className="unread-dot" /> : null}
@@ -821,7 +1103,7 @@ function App() {
               className="buffer-close"
               onClick={(event) => {
                 event.stopPropagation();
-                void handleCloseBuffer(connection.id, buffer.name);
+                void handleCloseBuffer(connection.id, key);
               }}
               title="Close"
             >
@@ -948,6 +1230,114 @@ function App() {
                 placeholder="Optional"
               />
             </label>
+            <label>
+              SASL
+              <select
+                value={connectForm.saslMechanism}
+                onChange={(event) =>
+                  setConnectForm((prev) => ({
+                    ...prev,
+                    saslMechanism: event.target.value as SaslMechanism,
+                  }))
+                }
+              >
+                <option value="none">None</option>
+                <option value="scram">SCRAM-SHA-256 (account and password)</option>
+                <option value="plain">PLAIN (account and password)</option>
+                <option value="external">EXTERNAL (client certificate)</option>
+              </select>
+            </label>
+            {connectForm.saslMechanism === "plain" ||
+            connectForm.saslMechanism === "scram" ? (
+              <>
+                <label>
+                  SASL account
+                  <input
+                    value={connectForm.saslAccount}
+                    onChange={(event) =>
+                      setConnectForm((prev) => ({
+                        ...prev,
+                        saslAccount: event.target.value,
+                      }))
+                    }
+                    required
+                  />
+                </label>
+                <label>
+                  SASL password
+                  <input
+                    type="password"
+                    value={connectForm.saslPassword}
+                    onChange={(event) =>
+                      setConnectForm((prev) => ({
+                        ...prev,
+                        saslPassword: event.target.value,
+                      }))
+                    }
+                    required
+                  />
+                </label>
+              </>
+            ) : null}
+            <label>
+              Client certificate
+              <input
+                value={connectForm.clientCert}
+                onChange={(event) =>
+                  setConnectForm((prev) => ({
+                    ...prev,
+                    clientCert: event.target.value,
+                  }))
+                }
+                placeholder="Optional path to .p12 or .pem"
+              />
+            </label>
+            {connectForm.clientCert.trim() ? (
+              <>
+                <label>
+                  Client key
+                  <input
+                    value={connectForm.clientKey}
+                    onChange={(event) =>
+                      setConnectForm((prev) => ({
+                        ...prev,
+                        clientKey: event.target.value,
+                      }))
+                    }
+                    placeholder="PEM key path (leave empty for PKCS#12)"
+                  />
+                </label>
+                {connectForm.clientKey.trim() ? null : (
+                  <label>
+                    Certificate password
+                    <input
+                      type="password"
+                      value={connectForm.clientCertPassword}
+                      onChange={(event) =>
+                        setConnectForm((prev) => ({
+                          ...prev,
+                          clientCertPassword: event.target.value,
+                        }))
+                      }
+                      placeholder="Optional"
+                    />
+                  </label>
+                )}
+              </>
+            ) : null}
+            <label className="checkbox">
+              <input
+                type="checkbox"
+                checked={connectForm.saslRequired}
+                onChange={(event) =>
+                  setConnectForm((prev) => ({
+                    ...prev,
+                    saslRequired: event.target.checked,
+                  }))
+                }
+              />
+              Disconnect if SASL fails
+            </label>
             <label>
               Auto-join channels
               <input
@@ -997,7 +1387,15 @@ function App() {
                 </span>
                 <div>
                   <strong>{connection.server}</strong>
-                  <div className="subtitle">as {connection.nickname}</div>
+                  <div className="subtitle">
+                    as {connection.nickname}
+                    {connection.connected && connection.lagMs !== undefined
+                      ? ` · ${connection.lagMs} ms`
+                      : null}
+                    {connection.pendingLines
+                      ? ` · sending ${connection.pendingLines} more line${connection.pendingLines === 1 ? "" : "s"}...`
+                      : null}
+                  </div>
                 </div>
                 <button
                   type="button"
@@ -1043,6 +1441,9 @@ function App() {
           <div className="chat-meta">
             <h2>
               {activeBuffer ? bufferLabel(activeBuffer) : "No channel selected"}
+              {activeBuffer?.modes && Object.keys(activeBuffer.modes).length > 0 ? (
+                <span className="channel-modes">{formatModes(activeBuffer.modes)}</span>
+              ) : null}
             </h2>
             {activeBuffer?.kind === "channel" ? (
               isEditingTopic ? (
@@ -1063,7 +1464,7 @@ function App() {
                 </div>
               ) : (
                 <div className="topic-row">
-                  <p className="topic" title={activeBuffer.topic ?? "No topic"}>
+                  <p className="topic" title={topicTitle(activeBuffer)}>
                     {activeBuffer.topic ?? "No topic set"}
                   </p>
                   <button
@@ -1227,11 +1628,130 @@ function applyEvent(
       const id = payload.connection_id;
       const existing = prev[id];
       const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
-      const bufferName = payload.channel;
-      const buffer = cloneBuffer(connection.buffers[bufferName], bufferName);
-      buffer.topic = payload.topic;
-      buffer.unread = !isBufferActive(active, id, bufferName) || buffer.unread;
-      connection.buffers[bufferName] = buffer;
+      const key = bufferKey(connection, payload.channel);
+      const buffer = cloneBuffer(connection.buffers[key], payload.channel);
+      buffer.topic = payload.topic || undefined;
+      buffer.topicSetter = payload.setter ?? undefined;
+      buffer.topicTime = payload.set_at ?? undefined;
+      buffer.unread = !isBufferActive(active, id, key) || buffer.unread;
+      connection.buffers[key] = buffer;
+      next[id] = connection;
+      return next;
+    }
+    case "reconnecting":
+    case "reconnected": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      const serverBuffer = cloneBuffer(connection.buffers["*server"], "*server");
+      const text =
+        payload.type === "reconnecting"
+          ? `Reconnecting in ${Math.round(payload.delay_ms / 1000)}s (attempt ${payload.attempt})`
+          : `Reconnected after ${payload.attempt} attempt${payload.attempt === 1 ? "" : "s"}`;
+      serverBuffer.messages = appendMessage(
+        serverBuffer.messages,
+        makeSystemMessage(id, "*server", text, "info"),
+        MESSAGE_LIMIT
+      );
+      serverBuffer.unread = !isBufferActive(active, id, "*server");
+      connection.buffers["*server"] = serverBuffer;
+      next[id] = connection;
+      return next;
+    }
+    case "lag": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      connection.lagMs = payload.lag_ms;
+      next[id] = connection;
+      return next;
+    }
+    case "send_queue": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      connection.pendingLines = payload.pending;
+      next[id] = connection;
+      return next;
+    }
+    case "channel_member": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      const key = bufferKey(connection, payload.channel);
+      const buffer = connection.buffers[key];
+      if (!buffer) {
+        return prev;
+      }
+      const users = removeUser(connection.casemapping, buffer.users, payload.user.nick);
+      connection.buffers[key] = {
+        ...buffer,
+        users: addUser(connection.casemapping, users, payload.user.nick, payload.user.modes),
+      };
+      next[id] = connection;
+      return next;
+    }
+    case "channel_modes": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      const key = bufferKey(connection, payload.channel);
+      const buffer = connection.buffers[key];
+      if (!buffer) {
+        return prev;
+      }
+      connection.buffers[key] = { ...buffer, modes: payload.modes };
+      next[id] = connection;
+      return next;
+    }
+    case "nick": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      if (payload.own) {
+        connection.nickname = payload.new_nick;
+      }
+      for (const [key, buffer] of Object.entries(connection.buffers)) {
+        if (buffer.kind !== "channel") {
+          continue;
+        }
+        const member = buffer.users.find((user) =>
+          sameName(connection.casemapping, user.nick, payload.old_nick),
+        );
+        if (member) {
+          const users = removeUser(connection.casemapping, buffer.users, payload.old_nick);
+          connection.buffers[key] = {
+            ...buffer,
+            users: addUser(connection.casemapping, users, payload.new_nick, member.modes),
+          };
+        }
+      }
+      const oldKey = bufferKey(connection, payload.old_nick);
+      const newKey = bufferKey(connection, payload.new_nick);
+      const query = connection.buffers[oldKey];
+      if (query?.kind === "query" && (oldKey === newKey || !connection.buffers[newKey])) {
+        delete connection.buffers[oldKey];
+        connection.buffers[newKey] = { ...query, name: payload.new_nick };
+      }
+      next[id] = connection;
+      return next;
+    }
+    case "server_info": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      if (connection.casemapping !== payload.info.casemapping) {
+        connection.casemapping = payload.info.casemapping;
+        connection.buffers = rekeyBuffers(connection);
+      }
+      next[id] = connection;
+      return next;
+    }
+    case "capabilities": {
+      const id = payload.connection_id;
+      const existing = prev[id];
+      const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
+      connection.capabilities = payload.enabled.slice();
       next[id] = connection;
       return next;
     }
@@ -1239,10 +1759,10 @@ function applyEvent(
       const id = payload.connection_id;
       const existing = prev[id];
       const connection = cloneConnection(existing, id, existing?.server, existing?.nickname);
-      const bufferName = payload.channel;
-      const buffer = cloneBuffer(connection.buffers[bufferName], bufferName);
+      const key = bufferKey(connection, payload.channel);
+      const buffer = cloneBuffer(connection.buffers[key], payload.channel);
       buffer.users = normalizeUsers(payload.users);
-      connection.buffers[bufferName] = buffer;
+      connection.buffers[key] = buffer;
       next[id] = connection;
       return next;
     }
@@ -1269,21 +1789,28 @@ function applyEvent(
       ) {
         bufferName = "*server";
       }
-      const buffer = cloneBuffer(connection.buffers[bufferName], bufferName);
+      const key = bufferKey(connection, bufferName);
+      const buffer = cloneBuffer(connection.buffers[key], bufferName);
       buffer.messages = appendMessage(buffer.messages, normalized, MESSAGE_LIMIT);
       const mentioned = isMention(normalized, connection.nickname);
-      buffer.unread = !isBufferActive(active, id, bufferName) || mentioned;
+      buffer.unread = !isBufferActive(active, id, key) || mentioned;
 
       if (buffer.kind === "channel") {
         if (msg.kind === "join" && msg.sender) {
-          buffer.users = addUser(buffer.users, msg.sender);
+          buffer.users = addUser(connection.casemapping, buffer.users, msg.sender);
         }
-        if (msg.kind === "part" && msg.sender) {
-          buffer.users = removeUser(buffer.users, msg.sender);
+        if ((msg.kind === "part" || msg.kind === "quit") && msg.sender) {
+          buffer.users = removeUser(connection.casemapping, buffer.users, msg.sender);
+        }
+        const victim = (msg.metadata as Record<string, unknown> | undefined)?.victim;
+        if (msg.kind === "kick" && typeof victim === "string") {
+          buffer.users = sameName(connection.casemapping, victim, connection.nickname)
+            ? []
+            : removeUser(connection.casemapping, buffer.users, victim);
         }
       }
 
-      connection.buffers[bufferName] = buffer;
+      connection.buffers[key] = buffer;
       next[id] = connection;
       return next;
     }
@@ -1292,6 +1819,32 @@ function applyEvent(
   }
 }
 
+/** Rebuilds a connection and its channel buffers from `irc_connection_state`. */
+function hydrateConnection(
+  prev: ConnectionsState,
+  state: ConnectionStatePayload,
+): ConnectionsState {
+  const id = state.connectionId;
+  const connection = cloneConnection(prev[id], id, state.server, state.nickname);
+  connection.connected = state.connected;
+  connection.capabilities = state.capabilities.slice();
+  if (connection.casemapping !== state.serverInfo.casemapping) {
+    connection.casemapping = state.serverInfo.casemapping;
+    connection.buffers = rekeyBuffers(connection);
+  }
+  for (const channel of state.channels) {
+    const key = bufferKey(connection, channel.name);
+    const buffer = cloneBuffer(connection.buffers[key], channel.name);
+    buffer.topic = channel.topic ?? undefined;
+    buffer.topicSetter = channel.topicSetter ?? undefined;
+    buffer.topicTime = channel.topicTime ?? undefined;
+    buffer.modes = { ...channel.modes };
+    buffer.users = normalizeUsers(channel.members);
+    connection.buffers[key] = buffer;
+  }
+  return { ...prev, [id]: connection };
+}
+
 function cloneConnection(
   existing: ConnectionState | undefined,
   id: string,
@@ -1311,6 +1864,8 @@ function cloneConnection(
     server: server ?? "unknown",
     nickname: nickname ?? "unknown",
     connected: false,
+    capabilities: [],
+    casemapping: "rfc1459",
     buffers: {
       "*server": createBuffer("*server", "status"),
     },
@@ -1362,21 +1917,22 @@ function resolveBufferName(
   connection: ConnectionState,
   msg: ChatMessage,
 ): string {
-  if (msg.kind === "info" || msg.kind === "error" || msg.kind === "quit") {
+  if (msg.kind === "info" || msg.kind === "error") {
     return "*server";
   }
   const target = msg.target;
-  if (target === "*" || equalsIgnoreCase(target, connection.server)) {
+  if (target === "*" || target.toLowerCase() === connection.server.toLowerCase()) {
     return "*server";
   }
   if (target.startsWith("#") || target.startsWith("&")) {
     return target;
   }
   const nickname = connection.nickname;
-  if (msg.sender && equalsIgnoreCase(target, nickname)) {
+  const toUs = sameName(connection.casemapping, target, nickname);
+  if (msg.sender && toUs) {
     return msg.sender;
   }
-  if (!msg.sender && equalsIgnoreCase(target, nickname)) {
+  if (!msg.sender && toUs) {
     return "*server";
   }
   return target;
@@ -1407,8 +1963,47 @@ function isMention(msg: ChatMessage, nickname: string): boolean {
   return (msg.message ?? "").toLowerCase().includes(nickname.toLowerCase());
 }
 
-function equalsIgnoreCase(a: string, b: string): boolean {
-  return a.localeCompare(b, undefined, { sensitivity: "accent" }) === 0;
+/** Folds a nick or channel name the way the server compares them. */
+function foldCase(casemapping: CaseMapping, name: string): string {
+  let folded = "";
+  for (const ch of name) {
+    if (ch >= "A" && ch <= "Z") {
+      folded += ch.toLowerCase();
+    } else if (casemapping === "ascii") {
+      folded += ch;
+    } else if (ch === "[") {
+      folded += "{";
+    } else if (ch === "]") {
+      folded += "}";
+    } else if (ch === "\\") {
+      folded += "|";
+    } else if (ch === "~" && casemapping === "rfc1459") {
+      folded += "^";
+    } else {
+      folded += ch;
+    }
+  }
+  return folded;
+}
+
+function sameName(casemapping: CaseMapping, a: string, b: string): boolean {
+  return foldCase(casemapping, a) === foldCase(casemapping, b);
+}
+
+function bufferKey(connection: ConnectionState, name: string): string {
+  return foldCase(connection.casemapping, name);
+}
+
+function rekeyBuffers(connection: ConnectionState): Record<string, BufferState> {
+  const buffers: Record<string, BufferState> = {};
+  for (const buffer of Object.values(connection.buffers)) {
+    const key = bufferKey(connection, buffer.name);
+    const existing = buffers[key];
+    buffers[key] = existing
+      ? { ...existing, messages: mergeHistory(existing.messages, buffer.messages) }
+      : buffer;
+  }
+  return buffers;
 }
 
 function makeSystemMessage(
@@ -1462,15 +2057,20 @@ function isBufferActive(
   return active.connectionId === connectionId && active.buffer === bufferName;
 }
 
-function addUser(users: ChannelUser[], nick: string, modes: string[] = []): ChannelUser[] {
-  if (users.some((user) => equalsIgnoreCase(user.nick, nick))) {
+function addUser(
+  casemapping: CaseMapping,
+  users: ChannelUser[],
+  nick: string,
+  modes: string[] = [],
+): ChannelUser[] {
+  if (users.some((user) => sameName(casemapping, user.nick, nick))) {
     return users;
   }
   return sortUsers([...users, { nick, modes }]);
 }
 
-function removeUser(users: ChannelUser[], nick: string): ChannelUser[] {
-  return users.filter((user) => !equalsIgnoreCase(user.nick, nick));
+function removeUser(casemapping: CaseMapping, users: ChannelUser[], nick: string): ChannelUser[] {
+  return users.filter((user) => !sameName(casemapping, user.nick, nick));
 }
 
 function sortUsers(users: ChannelUser[]): ChannelUser[] {
@@ -1501,6 +2101,26 @@ function modeBadge(modes: string[]): { symbol: string; className: string } | nul
   return null;
 }
 
+function topicTitle(buffer: BufferState): string {
+  if (!buffer.topic) {
+    return "No topic";
+  }
+  if (!buffer.topicSetter) {
+    return buffer.topic;
+  }
+  const when = buffer.topicTime ? ` on ${new Date(buffer.topicTime).toLocaleString()}` : "";
+  return `${buffer.topic}\n\nSet by ${buffer.topicSetter}${when}`;
+}
+
+function formatModes(modes: Record<string, string | null>): string {
+  const letters = Object.keys(modes).sort().join("");
+  const params = Object.keys(modes)
+    .sort()
+    .map((mode) => modes[mode])
+    .filter((param): param is string => Boolean(param));
+  return [`+${letters}`, ...params].join(" ");
+}
+
 function bufferLabel(buffer: BufferState): string {
   if (buffer.name === "*server") {
     return "Server";
@@ -1526,6 +2146,9 @@ function renderMessage(msg: ChatMessage, nickname: string) {
     case "join":
     case "part":
     case "quit":
+    case "nick":
+    case "kick":
+    case "mode":
     case "topic":
     case "info":
       return (
@@ -1637,11 +2260,28 @@ async function handleSlashCommand(
           setStatus("Usage: /me action text");
           return;
         }
-        await invoke("irc_send_message", {
+        await invoke("irc_send_action", {
           args: {
             connectionId: connection.id,
             target: buffer.name,
-            message: `\u0001ACTION ${action}\u0001`,
+            message: action,
+          },
+        });
+        return;
+      }
+      case "ctcp": {
+        const target = rest[0];
+        const ctcpCommand = rest[1];
+        if (!target || !ctcpCommand) {
+          setStatus("Usage: /ctcp nick command [params]");
+          return;
+        }
+        await invoke("irc_ctcp", {
+          args: {
+            connectionId: connection.id,
+            target,
+            command: ctcpCommand,
+            params: rest.slice(2).join(" ") || undefined,
           },
         });
         return;
@@ -1651,6 +2291,6 @@ async function handleSlashCommand(
     }
   } catch (error) {
     console.error(error);
-    setStatus(`Command failed: ${String(error)}`);
+    setStatus(`Command failed: ${errorMessage(error)}`);
   }
 }
@@ -6,6 +6,8 @@ export type MessageKind =
   | "part"
     | "quit"
     | "nick"
+    | "kick"
+    | "mode"
     | "topic"
     | "info"
     | "error";